@@ -1,4 +1,5 @@
-use std::path::PathBuf;
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::path::{Path, PathBuf};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -7,15 +8,21 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // extract the symbol table, the kernel receives it as its ramdisk
+    let symbols_path = out_dir.join("kernel.sym");
+    write_symbol_map(&kernel, &symbols_path);
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -23,3 +30,42 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Writes all function symbols of the kernel into a compact table.
+///
+/// Layout (little endian), read by `kernel/src/stacktrace/symbols.rs`:
+/// - magic `b"DSYM"`, `u32` entry count
+/// - entries sorted by address: `u64` start, `u64` size, `u32` name offset, `u32` name length
+/// - names, offsets are relative to the end of the entries
+fn write_symbol_map(kernel: &Path, out: &Path) {
+    let data = std::fs::read(kernel).unwrap();
+    let elf = object::File::parse(&*data).unwrap();
+
+    let mut symbols = elf
+        .symbols()
+        .filter(|sym| sym.kind() == SymbolKind::Text && sym.address() != 0)
+        .filter_map(|sym| {
+            let name = format!("{:#}", rustc_demangle::demangle(sym.name().ok()?));
+            Some((sym.address(), sym.size(), name))
+        })
+        .collect::<Vec<_>>();
+    symbols.sort_by_key(|(addr, _, _)| *addr);
+    symbols.dedup_by_key(|(addr, _, _)| *addr);
+
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+
+    table.extend_from_slice(b"DSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+
+    for (addr, size, name) in &symbols {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    table.extend_from_slice(&names);
+    std::fs::write(out, table).unwrap();
+}
@@ -0,0 +1,31 @@
+use crate::acpi::{read_u64, SDT_HEADER_SIZE};
+use log::warn;
+use x86_64::PhysAddr;
+
+/// Address space id of system memory in a generic address structure.
+const SYSTEM_MEMORY: u8 = 0;
+
+/// The high precision event timer described by the HPET table.
+#[derive(Copy, Clone, Debug)]
+pub struct HpetInfo {
+    /// Physical address of the registers.
+    pub address: PhysAddr,
+    /// Sequence number of this timer block.
+    pub number: u8,
+}
+
+impl HpetInfo {
+    pub(super) fn parse(table: &[u8]) -> Option<Self> {
+        // the registers are described by a generic address structure after the block id
+        let address_space = *table.get(SDT_HEADER_SIZE + 4)?;
+        if address_space != SYSTEM_MEMORY {
+            warn!("HPET in address space {address_space}, not system memory");
+            return None;
+        }
+
+        Some(Self {
+            address: PhysAddr::new(read_u64(table, SDT_HEADER_SIZE + 8)?),
+            number: *table.get(SDT_HEADER_SIZE + 16)?,
+        })
+    }
+}
@@ -0,0 +1,170 @@
+use crate::acpi::{read_u16, read_u32, read_u64, SDT_HEADER_SIZE};
+use crate::apic::{Polarity, Trigger};
+use alloc::vec::Vec;
+use log::warn;
+use x86_64::PhysAddr;
+
+/// Flag in the MADT header telling that the legacy 8259 PICs are present.
+const PCAT_COMPAT: u32 = 1;
+/// Flag of processor entries telling that the processor can be used.
+const ENABLED: u32 = 1;
+
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+const LOCAL_X2APIC_NMI: u8 = 10;
+
+/// Processor ids meaning "all processors" in NMI entries.
+const ALL_PROCESSORS: u32 = 0xFF;
+const ALL_X2_PROCESSORS: u32 = 0xFFFF_FFFF;
+
+/// The interrupt controllers described by the MADT.
+#[derive(Debug)]
+pub struct Madt {
+    /// Physical address of the local APIC's registers.
+    pub local_apic: PhysAddr,
+    /// Whether the legacy 8259 PICs are present as well.
+    pub has_8259: bool,
+    pub local_apics: Vec<LocalApicInfo>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<SourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApicInfo {
+    pub processor: u32,
+    pub apic_id: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ connected to another GSI or with another polarity or trigger mode than usual.
+#[derive(Copy, Clone, Debug)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// A local APIC interrupt pin the NMI is connected to.
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApicNmi {
+    /// ACPI processor id, `None` for all processors.
+    pub processor: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+impl Madt {
+    pub(super) fn parse(table: &[u8]) -> Option<Self> {
+        let mut madt = Madt {
+            local_apic: PhysAddr::new(read_u32(table, SDT_HEADER_SIZE)? as u64),
+            has_8259: read_u32(table, SDT_HEADER_SIZE + 4)? & PCAT_COMPAT != 0,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut at = SDT_HEADER_SIZE + 8;
+        while at + 2 <= table.len() {
+            let (ty, len) = (table[at], table[at + 1] as usize);
+            let Some(entry) = table.get(at..at + len).filter(|_| len >= 2) else {
+                warn!("Truncated MADT entry of type {ty}");
+                break;
+            };
+
+            if madt.add(ty, entry).is_none() {
+                warn!("Invalid MADT entry of type {ty}");
+            }
+            at += len;
+        }
+
+        Some(madt)
+    }
+
+    fn add(&mut self, ty: u8, entry: &[u8]) -> Option<()> {
+        match ty {
+            LOCAL_APIC => {
+                if read_u32(entry, 4)? & ENABLED != 0 {
+                    self.local_apics.push(LocalApicInfo {
+                        processor: *entry.get(2)? as u32,
+                        apic_id: *entry.get(3)? as u32,
+                    });
+                }
+            }
+            LOCAL_X2APIC => {
+                if read_u32(entry, 8)? & ENABLED != 0 {
+                    self.local_apics.push(LocalApicInfo {
+                        processor: read_u32(entry, 12)?,
+                        apic_id: read_u32(entry, 4)?,
+                    });
+                }
+            }
+            IO_APIC => self.io_apics.push(IoApicInfo {
+                id: *entry.get(2)?,
+                address: PhysAddr::new(read_u32(entry, 4)? as u64),
+                gsi_base: read_u32(entry, 8)?,
+            }),
+            SOURCE_OVERRIDE => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 8)?);
+                self.overrides.push(SourceOverride {
+                    irq: *entry.get(3)?,
+                    gsi: read_u32(entry, 4)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_APIC_NMI => {
+                let processor = *entry.get(2)? as u32;
+                let (polarity, trigger) = inti_flags(read_u16(entry, 3)?);
+                self.nmis.push(LocalApicNmi {
+                    processor: (processor != ALL_PROCESSORS).then_some(processor),
+                    lint: *entry.get(5)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_X2APIC_NMI => {
+                let processor = read_u32(entry, 4)?;
+                let (polarity, trigger) = inti_flags(read_u16(entry, 2)?);
+                self.nmis.push(LocalApicNmi {
+                    processor: (processor != ALL_X2_PROCESSORS).then_some(processor),
+                    lint: *entry.get(8)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_APIC_ADDRESS => self.local_apic = PhysAddr::new(read_u64(entry, 4)?),
+            _ => (),
+        }
+
+        Some(())
+    }
+}
+
+/// Decodes the MPS INTI flags, "conforming to the bus" means ISA behaviour for us.
+fn inti_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::Low,
+        _ => Polarity::High,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+
+    (polarity, trigger)
+}
@@ -0,0 +1,176 @@
+mod hpet;
+mod madt;
+
+pub use hpet::HpetInfo;
+pub use madt::{IoApicInfo, LocalApicNmi, Madt, SourceOverride};
+
+use crate::mem::MemoryManager;
+use conquer_once::spin::OnceCell;
+use core::slice;
+use log::{debug, info, warn};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the RSDP of ACPI 1.0, later revisions extend it.
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+const SDT_HEADER_SIZE: usize = 36;
+
+static ACPI: OnceCell<AcpiInfo> = OnceCell::uninit();
+
+/// Everything the kernel needs from the ACPI tables.
+///
+/// It is copied out of the tables, so their memory can be reclaimed afterwards.
+pub struct AcpiInfo {
+    pub madt: Option<Madt>,
+    pub hpet: Option<HpetInfo>,
+}
+
+/// The tables listed by the RSDT or XSDT.
+struct Tables<'a> {
+    mem: &'a MemoryManager,
+    /// Body of the RSDT or XSDT.
+    entries: &'a [u8],
+    /// Whether the entries are 64 bit wide, as in the XSDT.
+    wide: bool,
+}
+
+/// Parses the ACPI tables reachable from the RSDP at `rsdp` and keeps what the kernel needs.
+///
+/// Needs the heap. The tables are read through the physical memory window.
+///
+/// # Safety
+/// `rsdp` has to be the address of the RSDP handed over by the firmware and the
+/// tables may not have been reclaimed yet.
+pub unsafe fn init(mem: &MemoryManager, rsdp: PhysAddr) -> Option<&'static AcpiInfo> {
+    // SAFETY: guaranteed by the caller
+    let Some((revision, tables)) = (unsafe { Tables::new(mem, rsdp) }) else {
+        warn!("Invalid ACPI RSDP at {rsdp:?}");
+        return None;
+    };
+
+    info!(
+        "ACPI revision {revision}, {} tables",
+        tables.entries.len() / if tables.wide { 8 } else { 4 }
+    );
+    for table in tables.iter() {
+        debug!("    {}", signature(table));
+    }
+
+    let info = AcpiInfo {
+        madt: tables.find(b"APIC").and_then(Madt::parse),
+        hpet: tables.find(b"HPET").and_then(HpetInfo::parse),
+    };
+
+    Some(ACPI.get_or_init(|| info))
+}
+
+impl<'a> Tables<'a> {
+    /// Returns the ACPI revision and the tables listed by the RSDP.
+    ///
+    /// # Safety
+    /// See `init`.
+    unsafe fn new(mem: &'a MemoryManager, rsdp: PhysAddr) -> Option<(u8, Self)> {
+        // SAFETY: guaranteed by the caller, the RSDP is at least as large as in ACPI 1.0
+        let v1 = unsafe { phys_slice(mem, rsdp, RSDP_V1_SIZE) };
+        if v1.get(..8)? != RSDP_SIGNATURE || !checksum_ok(v1) {
+            return None;
+        }
+
+        let revision = v1[15];
+        let (root, wide) = if revision >= 2 {
+            // SAFETY: revision 2 and later use the extended RSDP
+            let v2 = unsafe { phys_slice(mem, rsdp, RSDP_V2_SIZE) };
+            let length = read_u32(v2, 20)? as usize;
+            // SAFETY: as above, `length` covers the whole structure
+            if length < RSDP_V2_SIZE || !checksum_ok(unsafe { phys_slice(mem, rsdp, length) }) {
+                return None;
+            }
+
+            (read_u64(v2, 24)?, true)
+        } else {
+            (read_u32(v1, 16)? as u64, false)
+        };
+
+        // SAFETY: the RSDP points to a valid table
+        let root = unsafe { sdt(mem, PhysAddr::new(root)) }?;
+        let expected: &[u8; 4] = if wide { b"XSDT" } else { b"RSDT" };
+        if &root[..4] != expected {
+            return None;
+        }
+
+        let tables = Self {
+            mem,
+            entries: &root[SDT_HEADER_SIZE..],
+            wide,
+        };
+        Some((revision, tables))
+    }
+
+    /// Every table with a valid checksum.
+    fn iter(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let width = if self.wide { 8 } else { 4 };
+
+        self.entries.chunks_exact(width).filter_map(|entry| {
+            let addr = match self.wide {
+                true => read_u64(entry, 0)?,
+                false => read_u32(entry, 0)? as u64,
+            };
+
+            // SAFETY: the RSDT and XSDT only point to valid tables
+            let table = unsafe { sdt(self.mem, PhysAddr::new(addr)) };
+            if table.is_none() {
+                warn!("Invalid ACPI table at 0x{addr:X}");
+            }
+            table
+        })
+    }
+
+    fn find(&self, sig: &[u8; 4]) -> Option<&'a [u8]> {
+        self.iter().find(|table| &table[..4] == sig)
+    }
+}
+
+/// The whole table at `addr`, if its checksum is fine.
+///
+/// # Safety
+/// `addr` has to point to an ACPI table.
+unsafe fn sdt(mem: &MemoryManager, addr: PhysAddr) -> Option<&[u8]> {
+    // SAFETY: guaranteed by the caller
+    let header = unsafe { phys_slice(mem, addr, SDT_HEADER_SIZE) };
+    let length = read_u32(header, 4)? as usize;
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+
+    // SAFETY: the header tells the size of the table
+    let table = unsafe { phys_slice(mem, addr, length) };
+    checksum_ok(table).then_some(table)
+}
+
+/// # Safety
+/// The memory has to be readable through the physical memory window.
+unsafe fn phys_slice(mem: &MemoryManager, addr: PhysAddr, len: usize) -> &[u8] {
+    // SAFETY: guaranteed by the caller
+    unsafe { slice::from_raw_parts(mem.translate::<u8>(addr), len) }
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn signature(table: &[u8]) -> &str {
+    core::str::from_utf8(&table[..4]).unwrap_or("????")
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
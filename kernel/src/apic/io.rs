@@ -0,0 +1,135 @@
+use crate::acpi::IoApicInfo;
+use crate::apic::{Polarity, Trigger};
+use crate::mem::{CacheMode, MemoryManager, RegionError};
+use x86_64::VirtAddr;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// Routes global system interrupts to the local APICs.
+pub struct IoApic {
+    id: u8,
+    mmio: VirtAddr,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC and masks all its pins.
+    ///
+    /// # Safety
+    /// `info` has to describe an I/O APIC present in the system.
+    pub(super) unsafe fn new(mem: &MemoryManager, info: &IoApicInfo) -> Result<Self, RegionError> {
+        // SAFETY: guaranteed by the caller, the registers are device memory
+        let mmio = unsafe { mem.map_mmio(info.address, 0x20, CacheMode::Uncacheable) }?;
+
+        let mut this = Self {
+            id: info.id,
+            mmio,
+            gsi_base: info.gsi_base,
+            pins: 0,
+        };
+        this.pins = ((this.read(VERSION) >> 16) & 0xFF) + 1;
+
+        for pin in 0..this.pins {
+            this.write_entry(pin, ENTRY_MASKED);
+        }
+
+        Ok(this)
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Global system interrupts handled by this I/O APIC.
+    pub fn gsis(&self) -> core::ops::Range<u32> {
+        self.gsi_base..self.gsi_base + self.pins
+    }
+
+    /// Delivers `gsi` to the local APIC `apic_id` with `vector`.
+    pub fn route(
+        &mut self,
+        gsi: u32,
+        vector: u8,
+        apic_id: u8,
+        polarity: Polarity,
+        trigger: Trigger,
+    ) {
+        let mut entry = vector as u64 | (apic_id as u64) << 56;
+        if polarity == Polarity::Low {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if trigger == Trigger::Level {
+            entry |= ENTRY_LEVEL;
+        }
+
+        self.write_entry(gsi - self.gsi_base, entry);
+    }
+
+    pub fn mask(&mut self, gsi: u32) {
+        let pin = gsi - self.gsi_base;
+        let entry = self.read_entry(pin);
+        self.write_entry(pin, entry | ENTRY_MASKED);
+    }
+
+    /// The vector, polarity and trigger mode `gsi` is delivered with, `None` while it is masked.
+    pub fn routing(&self, gsi: u32) -> Option<(u8, Polarity, Trigger)> {
+        let entry = self.read_entry(gsi - self.gsi_base);
+        if entry & ENTRY_MASKED != 0 {
+            return None;
+        }
+
+        let polarity = match entry & ENTRY_ACTIVE_LOW {
+            0 => Polarity::High,
+            _ => Polarity::Low,
+        };
+        let trigger = match entry & ENTRY_LEVEL {
+            0 => Trigger::Edge,
+            _ => Trigger::Level,
+        };
+        Some((entry as u8, polarity, trigger))
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let low = self.read(REDIRECTION_TABLE + pin * 2);
+        let high = self.read(REDIRECTION_TABLE + pin * 2 + 1);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn write_entry(&mut self, pin: u32, entry: u64) {
+        // mask first, so the pin never fires with half of the entry written
+        self.write(REDIRECTION_TABLE + pin * 2, ENTRY_MASKED as u32);
+        self.write(REDIRECTION_TABLE + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(REDIRECTION_TABLE + pin * 2, entry as u32);
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        // SAFETY: the registers are mapped, selecting and reading has no side effects
+        unsafe {
+            (self.mmio + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.mmio + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        // SAFETY: the registers are mapped, `&mut self` keeps the selection intact
+        unsafe {
+            (self.mmio + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.mmio + IOWIN)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+}
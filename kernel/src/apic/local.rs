@@ -0,0 +1,185 @@
+use crate::acpi::LocalApicNmi;
+use crate::apic::{Polarity, Trigger};
+use crate::interrupts::{APIC_ERROR_VECTOR, SPURIOUS_VECTOR};
+use crate::mem::{CacheMode, MemoryManager, RegionError};
+use core::arch::x86_64::__cpuid;
+use log::warn;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const BASE_ENABLE: u64 = 1 << 11;
+const BASE_X2APIC: u64 = 1 << 10;
+const BASE_ADDRESS: u64 = 0x000F_FFFF_FFFF_F000;
+/// MSR of the first register in x2APIC mode, the others follow at `offset / 16`.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+pub const ID: u32 = 0x20;
+pub const VERSION: u32 = 0x30;
+pub const TPR: u32 = 0x80;
+pub const EOI: u32 = 0xB0;
+pub const SVR: u32 = 0xF0;
+pub const ESR: u32 = 0x280;
+const ICR_LOW: u32 = 0x300;
+pub const LVT_TIMER: u32 = 0x320;
+pub const LVT_LINT0: u32 = 0x350;
+pub const LVT_LINT1: u32 = 0x360;
+pub const LVT_ERROR: u32 = 0x370;
+pub const TIMER_INITIAL: u32 = 0x380;
+pub const TIMER_CURRENT: u32 = 0x390;
+pub const TIMER_DIVIDE: u32 = 0x3E0;
+/// Only exists in x2APIC mode.
+const SELF_IPI: u32 = 0x3F0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+/// Destination shorthand of the ICR addressing the sending CPU.
+const ICR_SELF: u32 = 0b01 << 18;
+
+/// The interrupt controller built into the CPU, accessed through MMIO or, in x2APIC mode, MSRs.
+pub struct LocalApic {
+    /// Where the registers are mapped, `None` in x2APIC mode.
+    mmio: Option<VirtAddr>,
+}
+
+impl LocalApic {
+    /// Enables the local APIC, in x2APIC mode if the CPU supports it.
+    ///
+    /// Every local interrupt stays masked.
+    ///
+    /// # Safety
+    /// Has to be called only once, `base` has to be the physical address of its registers.
+    pub(super) unsafe fn init(mem: &MemoryManager, base: PhysAddr) -> Result<Self, RegionError> {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        // SAFETY: the MSR exists on every CPU with an APIC
+        let value = unsafe { msr.read() };
+
+        if value & BASE_ADDRESS != base.as_u64() {
+            warn!(
+                "Local APIC at 0x{:X}, but the MADT says {base:?}",
+                value & BASE_ADDRESS
+            );
+        }
+
+        let this = if has_x2apic() {
+            // SAFETY: x2APIC mode can only be entered from xAPIC mode
+            unsafe {
+                msr.write(value | BASE_ENABLE);
+                msr.write(value | BASE_ENABLE | BASE_X2APIC);
+            }
+            Self { mmio: None }
+        } else {
+            // SAFETY: enabling the APIC leaves its registers where they are
+            unsafe { msr.write(value | BASE_ENABLE) };
+
+            let base = PhysAddr::new(value & BASE_ADDRESS);
+            // SAFETY: the registers are device memory
+            let mmio = unsafe { mem.map_mmio(base, 4096, CacheMode::Uncacheable) }?;
+            Self { mmio: Some(mmio) }
+        };
+
+        // SAFETY: only the local APIC's own configuration changes, all of it masked
+        unsafe {
+            this.write(TPR, 0);
+            this.write(LVT_TIMER, LVT_MASKED);
+            this.write(LVT_LINT0, LVT_MASKED);
+            this.write(LVT_LINT1, LVT_MASKED);
+            this.write(LVT_ERROR, APIC_ERROR_VECTOR as u32);
+            this.write(SVR, SPURIOUS_VECTOR as u32 | SVR_ENABLE);
+        }
+        this.clear_errors();
+
+        this.eoi();
+        Ok(this)
+    }
+
+    /// Delivers the local interrupt pin the firmware connected the NMI to as NMI.
+    pub(super) fn set_nmi(&self, nmi: &LocalApicNmi) {
+        let mut lvt = LVT_NMI;
+        if nmi.polarity == Polarity::Low {
+            lvt |= LVT_ACTIVE_LOW;
+        }
+        if nmi.trigger == Trigger::Level {
+            lvt |= LVT_LEVEL;
+        }
+
+        let reg = if nmi.lint == 0 { LVT_LINT0 } else { LVT_LINT1 };
+        // SAFETY: the firmware tells us the NMI is connected there
+        unsafe { self.write(reg, lvt) };
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        self.mmio.is_none()
+    }
+
+    pub fn id(&self) -> u32 {
+        match self.mmio {
+            Some(_) => self.read(ID) >> 24,
+            None => self.read(ID),
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.read(VERSION) as u8
+    }
+
+    /// Signals the end of the interrupt being handled.
+    pub fn eoi(&self) {
+        // SAFETY: writing EOI only acknowledges the current interrupt
+        unsafe { self.write(EOI, 0) };
+    }
+
+    /// Raises `vector` on this CPU.
+    pub fn send_self_ipi(&self, vector: u8) {
+        // SAFETY: a fixed interrupt to ourselves only runs the handlers of the vector
+        unsafe {
+            match self.mmio {
+                Some(_) => self.write(ICR_LOW, ICR_SELF | vector as u32),
+                None => self.write(SELF_IPI, vector as u32),
+            }
+        }
+    }
+
+    /// Reads and resets the error status.
+    pub fn clear_errors(&self) -> u32 {
+        // SAFETY: the ESR has to be written before it can be read
+        unsafe { self.write(ESR, 0) };
+        self.read(ESR)
+    }
+
+    pub fn read(&self, reg: u32) -> u32 {
+        match self.mmio {
+            // SAFETY: the registers are mapped and reads have no side effects
+            Some(base) => unsafe { (base + reg as u64).as_ptr::<u32>().read_volatile() },
+            // SAFETY: every register has its MSR in x2APIC mode
+            None => unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).read() as u32 },
+        }
+    }
+
+    /// # Safety
+    /// Changing the local APIC's configuration can break interrupt delivery.
+    pub unsafe fn write(&self, reg: u32, value: u32) {
+        match self.mmio {
+            // SAFETY: the registers are mapped, the rest is up to the caller
+            Some(base) => unsafe {
+                (base + reg as u64)
+                    .as_mut_ptr::<u32>()
+                    .write_volatile(value)
+            },
+            // SAFETY: as above
+            None => unsafe { Msr::new(X2APIC_MSR_BASE + reg / 16).write(value as u64) },
+        }
+    }
+}
+
+/// Whether the CPU has a local APIC at all.
+pub fn has_apic() -> bool {
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+fn has_x2apic() -> bool {
+    __cpuid(1).ecx & (1 << 21) != 0
+}
@@ -0,0 +1,236 @@
+mod io;
+mod local;
+
+pub use io::IoApic;
+pub use local::{
+    has_apic, LocalApic, LVT_MASKED, LVT_TIMER, TIMER_CURRENT, TIMER_DIVIDE, TIMER_INITIAL,
+};
+
+use crate::acpi::{Madt, SourceOverride};
+use crate::interrupts::{self, IrqHandle, IrqReturn, Sharing, APIC_ERROR_VECTOR};
+use crate::mem::{MemoryManager, RegionError};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt::{Display, Formatter};
+use log::{error, info, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+static APIC: OnceCell<Apic> = OnceCell::uninit();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug)]
+pub enum IrqError {
+    /// The interrupt controllers have not been set up.
+    NoController,
+    /// No I/O APIC handles the GSI.
+    NoIoApic(u32),
+    /// Somebody else already handles the vector and does not share it.
+    InUse(u8),
+    /// The GSI is already routed with a different polarity or trigger mode.
+    Conflict(u32),
+    NoFreeVector,
+    /// The local APIC ID doesn't fit into the 8 bit destination of the I/O APIC,
+    /// reaching it would need interrupt remapping.
+    UnreachableApic(u32),
+}
+
+struct Apic {
+    local: LocalApic,
+    io: Spinlock<Vec<IoApic>>,
+    overrides: Vec<SourceOverride>,
+}
+
+/// Masks the legacy PICs, if any, and sets up the local APIC and every I/O APIC from the MADT.
+///
+/// Every GSI stays masked until a handler gets registered for it. Needs the heap.
+///
+/// # Safety
+/// May only be called once, `madt` has to describe this machine.
+pub unsafe fn init(mem: &MemoryManager, madt: &Madt) -> Result<(), RegionError> {
+    if madt.has_8259 {
+        interrupts::disable_pic();
+    }
+
+    // SAFETY: guaranteed by the caller
+    let local = unsafe { LocalApic::init(mem, madt.local_apic) }?;
+
+    let id = local.id();
+    let processor = madt
+        .local_apics
+        .iter()
+        .find(|lapic| lapic.apic_id == id)
+        .map(|lapic| lapic.processor);
+    for nmi in &madt.nmis {
+        if nmi.processor.is_none() || nmi.processor == processor {
+            local.set_nmi(nmi);
+        }
+    }
+
+    let mut io = Vec::new();
+    for info in &madt.io_apics {
+        // SAFETY: the MADT lists the I/O APICs present
+        let ioapic = unsafe { IoApic::new(mem, info) }?;
+        info!(
+            "I/O APIC {} at {:?}, GSIs {:?}",
+            ioapic.id(),
+            info.address,
+            ioapic.gsis()
+        );
+        io.push(ioapic);
+    }
+    if io.is_empty() {
+        warn!("No I/O APIC found, only local interrupts will work");
+    }
+
+    info!(
+        "Local APIC {id} enabled, version 0x{:X}{}",
+        local.version(),
+        if local.is_x2apic() {
+            " in x2APIC mode"
+        } else {
+            ""
+        }
+    );
+
+    APIC.init_once(|| Apic {
+        local,
+        io: Spinlock::new(io),
+        overrides: madt.overrides.clone(),
+    });
+    interrupts::register(
+        APIC_ERROR_VECTOR,
+        "APIC error",
+        Sharing::Exclusive,
+        local_apic_error,
+    )
+    .expect("the APIC error vector is free");
+
+    Ok(())
+}
+
+/// The local APIC of the CPU, once `init` has run.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    APIC.try_get().ok().map(|apic| &apic.local)
+}
+
+/// Acknowledges the interrupt being handled.
+pub fn eoi() {
+    if let Some(local) = local_apic() {
+        local.eoi();
+    }
+}
+
+/// Routes `gsi` to a free vector and calls `handler` for every interrupt on it.
+///
+/// If the GSI is routed already, `handler` gets added to its vector, as long as
+/// the line is configured the same way and every handler shares it.
+pub fn register_gsi(
+    gsi: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+    name: &'static str,
+    sharing: Sharing,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    let apic = APIC.try_get().map_err(|_| IrqError::NoController)?;
+
+    without_interrupts(|| {
+        let mut io = apic.io.lock();
+        let ioapic = io
+            .iter_mut()
+            .find(|ioapic| ioapic.gsis().contains(&gsi))
+            .ok_or(IrqError::NoIoApic(gsi))?;
+
+        if let Some((vector, routed_polarity, routed_trigger)) = ioapic.routing(gsi) {
+            if (routed_polarity, routed_trigger) != (polarity, trigger) {
+                return Err(IrqError::Conflict(gsi));
+            }
+            return interrupts::register(vector, name, sharing, handler);
+        }
+
+        let id = apic.local.id();
+        let apic_id = u8::try_from(id).map_err(|_| IrqError::UnreachableApic(id))?;
+
+        let vector = interrupts::alloc_vector().ok_or(IrqError::NoFreeVector)?;
+        // a freshly reserved vector has no handlers that could refuse
+        let handle = interrupts::register(vector, name, sharing, handler)?;
+        ioapic.route(gsi, vector, apic_id, polarity, trigger);
+
+        Ok(handle)
+    })
+}
+
+/// Like `register_gsi` for an ISA IRQ, taking the overrides of the firmware into account.
+pub fn register_isa_irq(
+    irq: u8,
+    name: &'static str,
+    sharing: Sharing,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    let (gsi, polarity, trigger) = isa_irq(irq)?;
+    register_gsi(gsi, polarity, trigger, name, sharing, handler)
+}
+
+/// Removes a handler of `gsi`, masking it and freeing its vector once it has none left.
+pub fn unregister_gsi(gsi: u32, handle: IrqHandle) -> Result<(), IrqError> {
+    let apic = APIC.try_get().map_err(|_| IrqError::NoController)?;
+
+    without_interrupts(|| {
+        let mut io = apic.io.lock();
+        let ioapic = io
+            .iter_mut()
+            .find(|ioapic| ioapic.gsis().contains(&gsi))
+            .ok_or(IrqError::NoIoApic(gsi))?;
+
+        let vector = handle.vector();
+        if interrupts::unregister(handle) == 0 {
+            ioapic.mask(gsi);
+            interrupts::free_vector(vector);
+        }
+
+        Ok(())
+    })
+}
+
+/// The GSI an ISA IRQ is connected to, with its polarity and trigger mode.
+pub fn isa_irq(irq: u8) -> Result<(u32, Polarity, Trigger), IrqError> {
+    let apic = APIC.try_get().map_err(|_| IrqError::NoController)?;
+
+    let config = match apic.overrides.iter().find(|o| o.irq == irq) {
+        Some(o) => (o.gsi, o.polarity, o.trigger),
+        None => (irq as u32, Polarity::High, Trigger::Edge),
+    };
+    Ok(config)
+}
+
+impl Display for IrqError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::NoController => write!(f, "no interrupt controller"),
+            Self::NoIoApic(gsi) => write!(f, "no I/O APIC handles GSI {gsi}"),
+            Self::InUse(vector) => write!(f, "vector {vector} is in use"),
+            Self::Conflict(gsi) => write!(f, "GSI {gsi} is routed differently already"),
+            Self::NoFreeVector => write!(f, "no free vector"),
+            Self::UnreachableApic(id) => write!(f, "local APIC {id} can't be targeted"),
+        }
+    }
+}
+
+fn local_apic_error() -> IrqReturn {
+    if let Some(local) = local_apic() {
+        error!("Local APIC error 0x{:X}", local.clear_errors());
+    }
+    IrqReturn::Handled
+}
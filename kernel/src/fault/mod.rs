@@ -0,0 +1,222 @@
+mod page;
+mod regs;
+
+pub use regs::Registers;
+
+use crate::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use crate::kpanic::kernel_panic;
+use core::fmt::{Display, Formatter};
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, SelectorErrorCode,
+};
+
+/// Mnemonic and name of every architectural exception vector.
+const EXCEPTIONS: [(&str, &str); 32] = [
+    ("#DE", "Divide Error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-Maskable Interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound Range Exceeded"),
+    ("#UD", "Invalid Opcode"),
+    ("#NM", "Device Not Available"),
+    ("#DF", "Double Fault"),
+    ("---", "Coprocessor Segment Overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment Not Present"),
+    ("#SS", "Stack-Segment Fault"),
+    ("#GP", "General Protection Fault"),
+    ("#PF", "Page Fault"),
+    ("---", "Reserved"),
+    ("#MF", "x87 Floating-Point Exception"),
+    ("#AC", "Alignment Check"),
+    ("#MC", "Machine Check"),
+    ("#XM", "SIMD Floating-Point Exception"),
+    ("#VE", "Virtualization Exception"),
+    ("#CP", "Control Protection Exception"),
+    ("---", "Reserved"),
+    ("---", "Reserved"),
+    ("---", "Reserved"),
+    ("---", "Reserved"),
+    ("---", "Reserved"),
+    ("---", "Reserved"),
+    ("#HV", "Hypervisor Injection Exception"),
+    ("#VC", "VMM Communication Exception"),
+    ("#SX", "Security Exception"),
+    ("---", "Reserved"),
+];
+
+/// Uniform crash report shared by all exception handlers.
+pub struct ExceptionReport<'a> {
+    vector: u8,
+    frame: InterruptStackFrameValue,
+    regs: Registers,
+    error_code: Option<u64>,
+    detail: Option<&'a dyn Display>,
+}
+
+impl<'a> ExceptionReport<'a> {
+    pub fn new(vector: u8, frame: InterruptStackFrameValue, regs: Registers) -> Self {
+        Self {
+            vector,
+            frame,
+            regs,
+            error_code: None,
+            detail: None,
+        }
+    }
+
+    pub fn with_error_code(mut self, error_code: u64) -> Self {
+        self.error_code = Some(error_code);
+        self
+    }
+
+    /// Additional exception specific information printed after the error code.
+    pub fn with_detail(mut self, detail: &'a dyn Display) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    fn has_selector_error(&self) -> bool {
+        // #TS, #NP, #SS and #GP
+        matches!(self.vector, 10..=13)
+    }
+}
+
+impl Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let (mnemonic, name) = EXCEPTIONS
+            .get(self.vector as usize)
+            .copied()
+            .unwrap_or(("---", "Unknown"));
+
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            name, mnemonic, self.vector
+        )?;
+
+        if let Some(err) = self.error_code {
+            write!(f, "error:    {err:#x}")?;
+
+            if self.has_selector_error() {
+                match SelectorErrorCode::new(err) {
+                    Some(sel) if sel.is_null() => write!(f, " (not selector related)")?,
+                    Some(sel) => write!(
+                        f,
+                        " (external={} table={:?} index={})",
+                        sel.external(),
+                        sel.descriptor_table(),
+                        sel.index()
+                    )?,
+                    None => write!(f, " (reserved bits set)")?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        if let Some(detail) = self.detail {
+            write!(f, "{detail}")?;
+        }
+
+        writeln!(f, "{:#?}", self.frame)?;
+        write!(f, "{}", self.regs)
+    }
+}
+
+macro_rules! exception_handler {
+    ($name:ident, $vector:literal) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            let regs = Registers::capture();
+            kernel_panic(ExceptionReport::new($vector, *stack_frame, regs))
+        }
+    };
+    ($name:ident, $vector:literal, err) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, err: u64) {
+            let regs = Registers::capture();
+            kernel_panic(ExceptionReport::new($vector, *stack_frame, regs).with_error_code(err))
+        }
+    };
+    ($name:ident, $vector:literal, !) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) -> ! {
+            let regs = Registers::capture();
+            kernel_panic(ExceptionReport::new($vector, *stack_frame, regs))
+        }
+    };
+    ($name:ident, $vector:literal, err, !) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, err: u64) -> ! {
+            let regs = Registers::capture();
+            kernel_panic(ExceptionReport::new($vector, *stack_frame, regs).with_error_code(err))
+        }
+    };
+}
+
+exception_handler!(divide_error_handler, 0);
+exception_handler!(debug_handler, 1);
+exception_handler!(nmi_handler, 2);
+exception_handler!(overflow_handler, 4);
+exception_handler!(bound_range_handler, 5);
+exception_handler!(invalid_opcode_handler, 6);
+exception_handler!(device_not_available_handler, 7);
+exception_handler!(double_fault_handler, 8, err, !);
+exception_handler!(invalid_tss_handler, 10, err);
+exception_handler!(segment_not_present_handler, 11, err);
+exception_handler!(stack_segment_handler, 12, err);
+exception_handler!(general_protection_handler, 13, err);
+exception_handler!(x87_floating_point_handler, 16);
+exception_handler!(alignment_check_handler, 17, err);
+exception_handler!(machine_check_handler, 18, !);
+exception_handler!(simd_floating_point_handler, 19);
+exception_handler!(virtualization_handler, 20);
+exception_handler!(control_protection_handler, 21, err);
+exception_handler!(hypervisor_injection_handler, 28);
+exception_handler!(vmm_communication_handler, 29, err);
+exception_handler!(security_handler, 30, err);
+
+/// Installs a handler for every architectural exception except `#BP`,
+/// which is left to the caller as it is not fatal.
+///
+/// `#DF`, `NMI` and `#MC` run on their own IST stacks, see `gdt`.
+pub fn install_exception_handlers(idt: &mut InterruptDescriptorTable) {
+    // SAFETY: the indices refer to valid stacks in the TSS loaded by `init_gdt`
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(NMI_IST_INDEX);
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+    }
+
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_handler);
+    idt.page_fault.set_handler_fn(page::page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception
+        .set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception
+        .set_handler_fn(hypervisor_injection_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
@@ -0,0 +1,177 @@
+use crate::fault::{ExceptionReport, Registers};
+use crate::kpanic::kernel_panic;
+use crate::mem::phys_offset;
+use core::fmt::{Display, Formatter};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+const LEVEL_NAMES: [&str; 4] = ["P4", "P3", "P2", "P1"];
+
+pub extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let regs = Registers::capture();
+    let addr = Cr2::read();
+
+    let detail = PageFaultDetail {
+        addr,
+        error_code,
+        walk: PageWalk::new(addr),
+    };
+
+    kernel_panic(
+        ExceptionReport::new(14, *stack_frame, regs)
+            .with_error_code(error_code.bits())
+            .with_detail(&detail),
+    )
+}
+
+struct PageFaultDetail {
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    walk: PageWalk,
+}
+
+/// Result of walking the active page table hierarchy for a single address.
+///
+/// The walk is done by hand on the raw tables behind CR3 rather than through the
+/// `OffsetPageTable` held by the `MemoryManager`, as the fault may have happened
+/// while its lock was held.
+struct PageWalk {
+    /// Entries that were visited, starting at P4.
+    entries: [Option<(PhysAddr, PageTableFlags)>; 4],
+    end: WalkEnd,
+}
+
+#[derive(Copy, Clone)]
+enum WalkEnd {
+    /// No physical memory offset was registered yet, the tables can not be read.
+    Unavailable,
+    /// The entry at the given level is not present.
+    NotPresent(usize),
+    /// The address is mapped, `usize` is the level of the last entry (1 or a huge page).
+    Mapped(usize),
+}
+
+impl PageWalk {
+    fn new(addr: VirtAddr) -> Self {
+        let mut walk = Self {
+            entries: [None; 4],
+            end: WalkEnd::Unavailable,
+        };
+
+        let Some(offset) = phys_offset() else {
+            return walk;
+        };
+
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+        let mut table_addr = Cr3::read().0.start_address();
+
+        for (level, index) in indices.into_iter().enumerate() {
+            // SAFETY: the physical memory offset maps all of physical memory and
+            //         every table we reach was referenced by a present entry.
+            let table = unsafe { &*(offset + table_addr.as_u64()).as_ptr::<PageTable>() };
+            let entry = &table[index];
+
+            walk.entries[level] = Some((entry.addr(), entry.flags()));
+
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                walk.end = WalkEnd::NotPresent(level);
+                return walk;
+            }
+
+            // huge pages can only appear in P3 and P2
+            if (level == 1 || level == 2) && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                walk.end = WalkEnd::Mapped(level);
+                return walk;
+            }
+
+            table_addr = entry.addr();
+        }
+
+        walk.end = WalkEnd::Mapped(3);
+        walk
+    }
+}
+
+impl Display for PageFaultDetail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let ec = self.error_code;
+        let yn = |flag: PageFaultErrorCode| if ec.contains(flag) { "yes" } else { "no" };
+
+        writeln!(f, "address:  {:#018x}", self.addr.as_u64())?;
+        writeln!(
+            f,
+            "flags:    present={} write={} user={} reserved={} fetch={}",
+            yn(PageFaultErrorCode::PROTECTION_VIOLATION),
+            yn(PageFaultErrorCode::CAUSED_BY_WRITE),
+            yn(PageFaultErrorCode::USER_MODE),
+            yn(PageFaultErrorCode::MALFORMED_TABLE),
+            yn(PageFaultErrorCode::INSTRUCTION_FETCH),
+        )?;
+        writeln!(f, "cause:    {}", self.cause())?;
+        write!(f, "{}", self.walk)
+    }
+}
+
+impl PageFaultDetail {
+    fn cause(&self) -> &'static str {
+        let ec = self.error_code;
+
+        if ec.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            "reserved bit set in a paging structure"
+        } else if !ec.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "page not present"
+        } else if ec.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from a no-execute page"
+        } else if ec.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to a read-only page"
+        } else if ec.contains(PageFaultErrorCode::USER_MODE) {
+            "user mode access to a supervisor page"
+        } else {
+            "protection violation"
+        }
+    }
+}
+
+impl Display for PageWalk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "page walk (CR3={:#x}):", Cr3::read().0.start_address())?;
+
+        for (level, entry) in self.entries.iter().enumerate() {
+            let Some((addr, flags)) = entry else {
+                break;
+            };
+            writeln!(
+                f,
+                "  {}: {:#014x} {:?}",
+                LEVEL_NAMES[level],
+                addr.as_u64(),
+                flags
+            )?;
+        }
+
+        match self.end {
+            WalkEnd::Unavailable => writeln!(f, "  physical memory offset unknown, no walk"),
+            WalkEnd::NotPresent(level) => {
+                writeln!(f, "  -> missing at {}", LEVEL_NAMES[level])
+            }
+            WalkEnd::Mapped(level) => {
+                let size = match level {
+                    1 => "1GiB",
+                    2 => "2MiB",
+                    _ => "4KiB",
+                };
+                writeln!(f, "  -> mapped by {} ({})", LEVEL_NAMES[level], size)
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use core::arch::asm;
+use core::fmt::{Display, Formatter};
+
+/// Snapshot of the general purpose and control registers.
+///
+/// This is best effort: by the time a handler captures them, its prologue may
+/// already have reused some of the general purpose registers.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Self::default();
+        let ptr = &mut regs as *mut Self;
+
+        // SAFETY: we only store into `regs`, the offsets match the `repr(C)` layout
+        unsafe {
+            asm!(
+                "mov [{r} + 0x00], rax",
+                "mov [{r} + 0x08], rbx",
+                "mov [{r} + 0x10], rcx",
+                "mov [{r} + 0x18], rdx",
+                "mov [{r} + 0x20], rsi",
+                "mov [{r} + 0x28], rdi",
+                "mov [{r} + 0x30], rbp",
+                "mov [{r} + 0x38], rsp",
+                "mov [{r} + 0x40], r8",
+                "mov [{r} + 0x48], r9",
+                "mov [{r} + 0x50], r10",
+                "mov [{r} + 0x58], r11",
+                "mov [{r} + 0x60], r12",
+                "mov [{r} + 0x68], r13",
+                "mov [{r} + 0x70], r14",
+                "mov [{r} + 0x78], r15",
+                "mov {t}, cr0",
+                "mov [{r} + 0x80], {t}",
+                "mov {t}, cr2",
+                "mov [{r} + 0x88], {t}",
+                "mov {t}, cr3",
+                "mov [{r} + 0x90], {t}",
+                "mov {t}, cr4",
+                "mov [{r} + 0x98], {t}",
+                r = in(reg) ptr,
+                t = out(reg) _,
+                options(nostack, preserves_flags),
+            );
+        }
+
+        regs
+    }
+}
+
+impl Display for Registers {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let rows = [
+            [("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx)],
+            [("rdx", self.rdx), ("rsi", self.rsi), ("rdi", self.rdi)],
+            [("rbp", self.rbp), ("rsp", self.rsp), ("r8 ", self.r8)],
+            [("r9 ", self.r9), ("r10", self.r10), ("r11", self.r11)],
+            [("r12", self.r12), ("r13", self.r13), ("r14", self.r14)],
+            [("r15", self.r15), ("cr0", self.cr0), ("cr2", self.cr2)],
+        ];
+
+        for row in rows {
+            for (name, value) in row {
+                write!(f, "{name}={value:016x} ")?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f, "cr3={:016x} cr4={:016x}", self.cr3, self.cr4)
+    }
+}
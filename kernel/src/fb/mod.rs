@@ -1,10 +1,13 @@
 mod color;
+mod panic;
+
+pub use panic::PanicScreen;
 
 use crate::fb::color::ColorMapper;
 use bootloader_api::info::FrameBuffer;
 use core::fmt::Write;
 use log::error;
-use noto_sans_mono_bitmap::{get_raster, FontWeight, RasterHeight};
+use noto_sans_mono_bitmap::{get_raster, FontWeight, RasterHeight, RasterizedChar};
 use spinning_top::Spinlock;
 
 const FALLBACK_CHAR: char = '?'; // '�'; // doesnt work apparantly :c
@@ -15,7 +18,7 @@ const FONT_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
 const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 
-const VERTICAL_STRIDE: usize = FONT_HEIGHT + LINE_SPACING;
+pub const VERTICAL_STRIDE: usize = FONT_HEIGHT + LINE_SPACING;
 
 pub struct SharedFrameBuffer(Spinlock<InnerFrameBuffer>);
 
@@ -27,6 +30,7 @@ struct InnerFrameBuffer {
     pos_y: usize,
 }
 
+#[allow(dead_code)]
 #[derive(Copy, Clone, Debug)]
 pub enum Float {
     Left,
@@ -58,6 +62,24 @@ impl SharedFrameBuffer {
         }))
     }
 
+    /// Takes the lock even if it is currently held, e.g. by the code that panicked.
+    ///
+    /// # Safety
+    /// Whoever held the lock must never run again, as they would now share the framebuffer.
+    pub unsafe fn force_lock(&self) -> PanicScreen<'_> {
+        if self.0.is_locked() {
+            // SAFETY: guaranteed by the caller
+            unsafe { self.0.force_unlock() };
+        }
+
+        PanicScreen::new(self.0.lock())
+    }
+
+    /// Writes `s` only if the lock is free, returns `None` otherwise.
+    pub fn try_write_str(&self, s: &str) -> Option<core::fmt::Result> {
+        Some(self.0.try_lock()?.write_str(s))
+    }
+
     pub fn reset(&self) {
         let mut this = self.0.lock();
 
@@ -76,7 +98,7 @@ impl SharedFrameBuffer {
 
         let mapper = this.mapper.clone();
 
-        let mut buf = this.fb.buffer_mut();
+        let buf = this.fb.buffer_mut();
 
         for y in 0..info.height {
             for x in 0..info.width {
@@ -148,57 +170,126 @@ impl SharedFrameBuffer {
 
 impl Write for &SharedFrameBuffer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let mut this = self.0.lock();
-        let info = this.fb.info();
+        self.0.lock().write_str(s)
+    }
+}
+
+impl Write for InnerFrameBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let info = self.fb.info();
 
         for c in s.chars() {
             match c {
                 '\n' => {
-                    this.new_line();
+                    self.new_line();
                     continue;
                 }
                 '\r' => {
-                    this.pos_x = 0;
+                    self.pos_x = 0;
                     continue;
                 }
                 _ => (),
             }
 
-            let cr = get_raster(c, FontWeight::Regular, FONT_RASTER_HEIGHT).unwrap_or_else(|| {
-                get_raster(FALLBACK_CHAR, FontWeight::Regular, FONT_RASTER_HEIGHT)
-                    .expect("this should be present")
-            });
-
-            if this.pos_x + cr.width() * info.bytes_per_pixel > info.width {
-                this.new_line();
-            }
-
-            let mapper = this.mapper.clone();
-
-            for y in 0..cr.height() {
-                for x in 0..cr.width() {
-                    let ax =
-                        ((this.pos_y + y) * info.stride + this.pos_x + x) * info.bytes_per_pixel;
-
-                    let l = cr.raster()[y][x];
-
-                    if l > 0 {}
-                    mapper.write(&mut this.fb.buffer_mut()[ax..], &[l, l, l])
-                }
+            if self.pos_x + char_width(c) * info.bytes_per_pixel > info.width {
+                self.new_line();
             }
 
-            this.pos_x += cr.width() + LETTER_SPACING;
+            let (x, y) = (self.pos_x, self.pos_y);
+            self.pos_x += self.draw_char(x, y, c, false) + LETTER_SPACING;
         }
 
         Ok(())
     }
 }
 
+fn raster(c: char) -> RasterizedChar {
+    get_raster(c, FontWeight::Regular, FONT_RASTER_HEIGHT).unwrap_or_else(|| {
+        get_raster(FALLBACK_CHAR, FontWeight::Regular, FONT_RASTER_HEIGHT)
+            .expect("this should be present")
+    })
+}
+
+pub fn char_width(c: char) -> usize {
+    raster(c).width()
+}
+
 impl InnerFrameBuffer {
     pub fn clear(&mut self) {
         self.fb.buffer_mut().fill(0x00);
     }
 
+    pub fn fill(&mut self, color: [u8; 3]) {
+        let info = self.fb.info();
+        let mapper = self.mapper.clone();
+        let buf = self.fb.buffer_mut();
+
+        for y in 0..info.height {
+            for x in 0..info.width {
+                mapper.write(
+                    &mut buf[(y * info.stride + x) * info.bytes_per_pixel..],
+                    &color,
+                );
+            }
+        }
+    }
+
+    /// Draws `c` with its top left corner at `x`, `y` and returns its width.
+    ///
+    /// Pixels outside the screen are skipped. With `transparent` only the glyph
+    /// itself is drawn, otherwise its background is painted black.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: char, transparent: bool) -> usize {
+        let info = self.fb.info();
+        let mapper = self.mapper.clone();
+        let cr = raster(c);
+
+        for (dy, row) in cr.raster().iter().enumerate() {
+            for (dx, &l) in row.iter().enumerate() {
+                if x + dx >= info.width || y + dy >= info.height || (transparent && l == 0) {
+                    continue;
+                }
+
+                let ax = ((y + dy) * info.stride + x + dx) * info.bytes_per_pixel;
+                mapper.write(&mut self.fb.buffer_mut()[ax..], &[l, l, l])
+            }
+        }
+
+        cr.width()
+    }
+
+    /// Draws an image with its top left corner at `x`, `y`, pixels outside the screen are skipped.
+    pub fn draw_rgb_at(
+        &mut self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        x: usize,
+        y: usize,
+    ) {
+        assert!(stride >= 3);
+        assert_eq!(img.len(), width * height * stride);
+
+        let info = self.fb.info();
+        let mapper = self.mapper.clone();
+
+        for dy in 0..height.min(info.height.saturating_sub(y)) {
+            for dx in 0..width.min(info.width.saturating_sub(x)) {
+                let ip = (dy * width + dx) * stride;
+                let ax = ((y + dy) * info.stride + x + dx) * info.bytes_per_pixel;
+
+                if stride == 4 && img[ip + 3] == 0 {
+                    continue;
+                }
+
+                mapper.write(
+                    &mut self.fb.buffer_mut()[ax..],
+                    &[img[ip], img[ip + 1], img[ip + 2]],
+                )
+            }
+        }
+    }
+
     pub fn new_line(&mut self) {
         self.pos_x = 0;
         self.pos_y += VERTICAL_STRIDE;
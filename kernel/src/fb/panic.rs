@@ -0,0 +1,103 @@
+use crate::fb::{char_width, InnerFrameBuffer, FONT_HEIGHT, LETTER_SPACING, VERTICAL_STRIDE};
+use core::fmt::Write;
+use spinning_top::SpinlockGuard;
+
+/// Exclusive access to the framebuffer for drawing the panic screen.
+///
+/// Unlike the console, nothing here scrolls: everything is drawn at fixed
+/// positions and text that does not fit is cut off.
+pub struct PanicScreen<'a> {
+    fb: SpinlockGuard<'a, InnerFrameBuffer>,
+}
+
+/// Rectangular area text gets written into, wrapping at its right edge.
+pub struct TextRegion<'a> {
+    fb: &'a mut InnerFrameBuffer,
+
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+
+    cursor_x: usize,
+    cursor_y: usize,
+}
+
+impl<'a> PanicScreen<'a> {
+    pub(super) fn new(fb: SpinlockGuard<'a, InnerFrameBuffer>) -> Self {
+        Self { fb }
+    }
+
+    /// Width and height in pixels.
+    pub fn size(&self) -> (usize, usize) {
+        let info = self.fb.fb.info();
+        (info.width, info.height)
+    }
+
+    pub fn fill(&mut self, color: [u8; 3]) {
+        self.fb.fill(color);
+    }
+
+    pub fn draw_rgb(
+        &mut self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        x: usize,
+        y: usize,
+    ) {
+        self.fb.draw_rgb_at(img, width, height, stride, x, y);
+    }
+
+    pub fn region(&mut self, x: usize, y: usize, width: usize, height: usize) -> TextRegion<'_> {
+        TextRegion {
+            fb: &mut self.fb,
+            x,
+            y,
+            width,
+            height,
+            cursor_x: 0,
+            cursor_y: 0,
+        }
+    }
+}
+
+impl TextRegion<'_> {
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        self.cursor_y += VERTICAL_STRIDE;
+    }
+}
+
+impl Write for TextRegion<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => {
+                    self.new_line();
+                    continue;
+                }
+                '\r' => {
+                    self.cursor_x = 0;
+                    continue;
+                }
+                _ => (),
+            }
+
+            if self.cursor_x + char_width(c) > self.width {
+                self.new_line();
+            }
+
+            // everything below the region is dropped
+            if self.cursor_y + FONT_HEIGHT > self.height {
+                return Ok(());
+            }
+
+            let (x, y) = (self.x + self.cursor_x, self.y + self.cursor_y);
+            self.cursor_x += self.fb.draw_char(x, y, c, true) + LETTER_SPACING;
+        }
+
+        Ok(())
+    }
+}
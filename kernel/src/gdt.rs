@@ -0,0 +1,108 @@
+use crate::mem::MemoryManager;
+use conquer_once::spin::Lazy;
+use log::warn;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Interrupt stack with a page in front of it that gets unmapped once the
+/// memory manager is up, so overflowing it faults instead of corrupting memory.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; 4096],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+static mut IST_STACKS: [IstStack; IST_COUNT] = {
+    const EMPTY: IstStack = IstStack {
+        guard: [0; 4096],
+        stack: [0; IST_STACK_SIZE],
+    };
+    [EMPTY; IST_COUNT]
+};
+
+/// Pointers to the IST stacks, in IST index order.
+fn ist_stacks() -> impl Iterator<Item = *const IstStack> {
+    let first = core::ptr::addr_of!(IST_STACKS).cast::<IstStack>();
+    (0..IST_COUNT).map(move |i| first.wrapping_add(i))
+}
+
+static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
+    let mut tss = TaskStateSegment::new();
+
+    // the TSS is packed, so its entries can only be assigned, not borrowed
+    for (i, ist) in ist_stacks().enumerate() {
+        // SAFETY: we only take the address, the stacks are never accessed through rust
+        let stack = unsafe { core::ptr::addr_of!((*ist).stack) };
+        // stacks grow downwards, so the top is the end of the array
+        tss.interrupt_stack_table[i] = VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64;
+    }
+
+    tss
+});
+
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+
+    (gdt, Selectors { code, data, tss })
+});
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Replaces the bootloader's GDT with our own and loads the TSS.
+///
+/// Has to run before `init_idt`, as the IDT references the IST entries.
+pub fn init_gdt() {
+    let (gdt, sel) = &*GDT;
+    gdt.load();
+
+    // SAFETY: the selectors point to valid descriptors in the GDT we just loaded
+    unsafe {
+        CS::set_reg(sel.code);
+        SS::set_reg(sel.data);
+        DS::set_reg(sel.data);
+        ES::set_reg(sel.data);
+        load_tss(sel.tss);
+    }
+}
+
+/// Whether `addr` lies within one of the IST stacks.
+pub fn on_ist_stack(addr: u64) -> bool {
+    ist_stacks().any(|ist| {
+        // SAFETY: we only take the address
+        let stack = unsafe { core::ptr::addr_of!((*ist).stack) } as u64;
+        (stack..stack + IST_STACK_SIZE as u64).contains(&addr)
+    })
+}
+
+/// Unmaps the guard page below every IST stack.
+pub fn protect_ist_stacks(mem: &MemoryManager) {
+    for ist in ist_stacks() {
+        // SAFETY: we only take the address
+        let guard = unsafe { core::ptr::addr_of!((*ist).guard) };
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(guard));
+
+        // SAFETY: the guard page is never used for anything
+        if let Err(err) = unsafe { mem.set_guard_page(page) } {
+            warn!("Unable to set IST guard page {:?}: {:?}", page, err);
+        }
+    }
+}
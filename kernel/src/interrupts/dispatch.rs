@@ -0,0 +1,228 @@
+use super::{DYNAMIC_VECTORS, FIRST_IRQ_VECTOR, FIXED_VECTORS, IRQ_VECTORS};
+use crate::apic::IrqError;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Every vector from `FIRST_IRQ_VECTOR` on, each with its own lock so handlers of
+/// different vectors don't get in each other's way.
+static VECTORS: [Spinlock<Vector>; IRQ_VECTORS] =
+    [const { Spinlock::new(Vector::new()) }; IRQ_VECTORS];
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a handler did with an interrupt.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// The device of the handler did not raise it, for shared lines.
+    NotMine,
+}
+
+/// Whether a vector may have more than one handler.
+///
+/// A vector is only shared if every handler on it agrees.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sharing {
+    Exclusive,
+    Shared,
+}
+
+/// Identifies a registered handler, to remove it again.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: u8,
+    id: u64,
+}
+
+/// Counters of one vector.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VectorStats {
+    /// Interrupts passed on to the handlers.
+    pub count: u64,
+    /// Interrupts none of the handlers claimed.
+    pub unhandled: u64,
+    /// Interrupts raised although no device asked for them.
+    pub spurious: u64,
+    pub handlers: usize,
+}
+
+/// Every vector that has handlers or has seen any interrupts, with its handlers.
+pub struct InterruptStats;
+
+struct Vector {
+    /// Handed out by `alloc_vector`, so it is not handed out again while it has no handlers.
+    reserved: bool,
+    sharing: Sharing,
+    actions: Vec<Action>,
+    stats: VectorStats,
+}
+
+struct Action {
+    id: u64,
+    name: &'static str,
+    handler: Box<dyn Fn() -> IrqReturn + Send + Sync>,
+    /// Interrupts this handler claimed.
+    count: u64,
+}
+
+/// Reserves a free vector for `register`.
+pub fn alloc_vector() -> Option<u8> {
+    without_interrupts(|| {
+        (DYNAMIC_VECTORS..FIXED_VECTORS).find(|&vector| {
+            let mut slot = VECTORS[index(vector)].lock();
+            let free = !slot.reserved && slot.actions.is_empty();
+            slot.reserved |= free;
+            free
+        })
+    })
+}
+
+/// Makes a vector reserved by `alloc_vector` available again.
+///
+/// Its handlers have to be removed already.
+pub fn free_vector(vector: u8) {
+    without_interrupts(|| {
+        let mut slot = VECTORS[index(vector)].lock();
+        debug_assert!(
+            slot.actions.is_empty(),
+            "vector {vector} still has handlers"
+        );
+        slot.reserved = false;
+    });
+}
+
+/// Calls `handler` for every interrupt on `vector`, after the handlers registered before.
+///
+/// Handlers run with interrupts disabled and may not register or remove handlers
+/// of the same vector.
+pub fn register(
+    vector: u8,
+    name: &'static str,
+    sharing: Sharing,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    assert!(
+        vector >= FIRST_IRQ_VECTOR,
+        "vector {vector} is an exception"
+    );
+
+    let handler = Box::new(handler);
+    without_interrupts(|| {
+        let mut slot = VECTORS[index(vector)].lock();
+        if !slot.actions.is_empty()
+            && (slot.sharing == Sharing::Exclusive || sharing == Sharing::Exclusive)
+        {
+            return Err(IrqError::InUse(vector));
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        slot.sharing = sharing;
+        slot.actions.push(Action {
+            id,
+            name,
+            handler,
+            count: 0,
+        });
+        Ok(IrqHandle { vector, id })
+    })
+}
+
+/// Removes a handler, returning how many are left on its vector.
+pub fn unregister(handle: IrqHandle) -> usize {
+    without_interrupts(|| {
+        let mut slot = VECTORS[index(handle.vector)].lock();
+        slot.actions.retain(|action| action.id != handle.id);
+        slot.actions.len()
+    })
+}
+
+/// The counters of `vector`.
+pub fn vector_stats(vector: u8) -> VectorStats {
+    without_interrupts(|| {
+        let slot = VECTORS[index(vector)].lock();
+        VectorStats {
+            handlers: slot.actions.len(),
+            ..slot.stats
+        }
+    })
+}
+
+impl IrqHandle {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            reserved: false,
+            sharing: Sharing::Exclusive,
+            actions: Vec::new(),
+            stats: VectorStats {
+                count: 0,
+                unhandled: 0,
+                spurious: 0,
+                handlers: 0,
+            },
+        }
+    }
+}
+
+impl Display for InterruptStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for vector in FIRST_IRQ_VECTOR..=u8::MAX {
+            without_interrupts(|| {
+                let slot = VECTORS[index(vector)].lock();
+                let stats = slot.stats;
+                if slot.actions.is_empty() && stats.count == 0 && stats.spurious == 0 {
+                    return Ok(());
+                }
+
+                write!(
+                    f,
+                    "{vector:>3}: {} interrupts, {} unhandled, {} spurious",
+                    stats.count, stats.unhandled, stats.spurious
+                )?;
+                for (i, action) in slot.actions.iter().enumerate() {
+                    let separator = if i == 0 { " - " } else { ", " };
+                    write!(f, "{separator}{} ({})", action.name, action.count)?;
+                }
+                writeln!(f)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts a spurious interrupt on `vector`, which doesn't reach the handlers.
+pub(super) fn count_spurious(vector: u8) {
+    VECTORS[index(vector)].lock().stats.spurious += 1;
+}
+
+/// Calls every handler of `vector`, returns whether it had any.
+pub(super) fn run_handlers(vector: u8) -> bool {
+    let mut slot = VECTORS[index(vector)].lock();
+    let Vector { actions, stats, .. } = &mut *slot;
+
+    stats.count += 1;
+    let mut handled = false;
+    for action in actions.iter_mut() {
+        if (action.handler)() == IrqReturn::Handled {
+            action.count += 1;
+            handled = true;
+        }
+    }
+    if !handled {
+        stats.unhandled += 1;
+    }
+
+    !actions.is_empty()
+}
+
+fn index(vector: u8) -> usize {
+    (vector - FIRST_IRQ_VECTOR) as usize
+}
@@ -0,0 +1,304 @@
+mod dispatch;
+
+pub use dispatch::{
+    alloc_vector, free_vector, register, unregister, vector_stats, InterruptStats, IrqHandle,
+    IrqReturn, Sharing,
+};
+
+use crate::apic::{self, IrqError};
+use crate::fault::install_exception_handlers;
+use crate::stacktrace::dump_stack;
+use conquer_once::spin::Lazy;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::{info, trace, warn};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// First vector after the CPU exceptions.
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+/// Vectors from here up to `FIXED_VECTORS` are handed out by `alloc_vector`,
+/// the 16 below belong to the legacy PICs.
+const DYNAMIC_VECTORS: u8 = 48;
+/// Vectors from here on are used by the local APIC.
+const FIXED_VECTORS: u8 = 0xF0;
+/// Raised by the local APIC on internal errors.
+pub const APIC_ERROR_VECTOR: u8 = 0xFE;
+/// Raised by the local APIC for interrupts that vanished before they could be delivered.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IRQ_VECTORS: usize = 256 - FIRST_IRQ_VECTOR as usize;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+/// IRQ of the first PIC the second one is connected to.
+const CASCADE_IRQ: u8 = 2;
+/// OCW3 selecting the in-service register.
+const READ_ISR: u8 = 0x0B;
+const EOI: u8 = 0x20;
+/// ISA IRQ routed by the self-test, only a second parallel port or a sound card would use it.
+const SELF_TEST_IRQ: u8 = 5;
+
+static PIC: Spinlock<Pic> = Spinlock::new(Pic {
+    command: (Port::new(PIC1_COMMAND), Port::new(PIC2_COMMAND)),
+    data: (Port::new(PIC1_DATA), Port::new(PIC2_DATA)),
+    masks: 0xFFFF,
+});
+/// Set when the legacy PICs deliver interrupts instead of the APIC.
+static USE_PIC: AtomicBool = AtomicBool::new(false);
+
+/// The two cascaded legacy 8259 PICs, remapped to the vectors right after the exceptions.
+struct Pic {
+    command: (Port<u8>, Port<u8>),
+    data: (Port<u8>, Port<u8>),
+    /// Bit `n` masks IRQ `n`.
+    masks: u16,
+}
+
+/// Points the IDT entries of all vectors from 32 on to `irq_entry`.
+macro_rules! install_irq_entries {
+    ($idt:ident, $($high:literal)*) => {
+        $( install_irq_entries!(@row $idt, $high, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15); )*
+    };
+    (@row $idt:ident, $high:literal, $($low:literal)*) => {
+        $( $idt[$high * 16 + $low].set_handler_fn(irq_entry::<{ $high * 16 + $low }>); )*
+    };
+}
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    install_exception_handlers(&mut idt);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    install_irq_entries!(idt, 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
+    idt
+});
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// Masks every IRQ of the legacy PICs, for when the APIC takes over.
+///
+/// They get remapped first, so the spurious interrupts they may still raise
+/// don't end up on an exception vector.
+pub fn disable_pic() {
+    without_interrupts(|| {
+        let mut pic = PIC.lock();
+        pic.remap();
+        pic.set_masks(0xFFFF);
+    });
+}
+
+/// Uses the legacy PICs to deliver interrupts, for machines without an APIC.
+///
+/// Every IRQ stays masked until a handler gets registered for it.
+pub fn init_pic() {
+    disable_pic();
+    USE_PIC.store(true, Ordering::Release);
+}
+
+/// Calls `handler` for every interrupt of the ISA IRQ `irq`, through the APIC or the legacy PICs.
+pub fn register_isa_irq(
+    irq: u8,
+    name: &'static str,
+    sharing: Sharing,
+    handler: impl Fn() -> IrqReturn + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    if !USE_PIC.load(Ordering::Acquire) {
+        return apic::register_isa_irq(irq, name, sharing, handler);
+    }
+
+    assert!(irq < 16, "there is no ISA IRQ {irq}");
+
+    without_interrupts(|| {
+        let handle = register(FIRST_IRQ_VECTOR + irq, name, sharing, handler)?;
+
+        let mut pic = PIC.lock();
+        let masks = pic.masks & !(1 << irq);
+        pic.set_masks(masks);
+
+        Ok(handle)
+    })
+}
+
+/// Removes a handler of the ISA IRQ `irq`, masking it once it has none left.
+pub fn unregister_isa_irq(irq: u8, handle: IrqHandle) -> Result<(), IrqError> {
+    if !USE_PIC.load(Ordering::Acquire) {
+        let (gsi, _, _) = apic::isa_irq(irq)?;
+        return apic::unregister_gsi(gsi, handle);
+    }
+
+    without_interrupts(|| {
+        if unregister(handle) == 0 {
+            let mut pic = PIC.lock();
+            let masks = pic.masks | 1 << irq;
+            pic.set_masks(masks);
+        }
+    });
+    Ok(())
+}
+
+/// Raises a spare vector shared by two handlers and removes them again, panics if
+/// the interrupt doesn't reach the right one. Then routes an ISA IRQ and tears it
+/// down, panics if its vector isn't freed.
+///
+/// Needs interrupts enabled, does nothing without a local APIC.
+pub fn self_test() {
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    let Some(local) = apic::local_apic() else {
+        return;
+    };
+    let vector = alloc_vector().expect("no free vector for the self-test");
+
+    let other = register(vector, "self-test", Sharing::Shared, || IrqReturn::NotMine)
+        .expect("the vector is free");
+    let mine = register(vector, "self-test", Sharing::Shared, || {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .expect("the vector is shared");
+
+    local.send_self_ipi(vector);
+    for _ in 0..1_000_000 {
+        if HITS.load(Ordering::Relaxed) != 0 {
+            break;
+        }
+        spin_loop();
+    }
+
+    let stats = vector_stats(vector);
+    assert_eq!(HITS.load(Ordering::Relaxed), 1, "self IPI not delivered");
+    assert_eq!((stats.count, stats.unhandled, stats.handlers), (1, 0, 2));
+
+    assert_eq!(unregister(other), 1);
+    assert_eq!(mine.vector(), vector);
+    assert_eq!(unregister(mine), 0);
+    free_vector(vector);
+
+    let isa = register_isa_irq(SELF_TEST_IRQ, "self-test", Sharing::Shared, || {
+        IrqReturn::NotMine
+    })
+    .expect("failed to route an ISA IRQ");
+    let vector = isa.vector();
+    unregister_isa_irq(SELF_TEST_IRQ, isa).expect("failed to remove an ISA IRQ");
+    // vectors are handed out lowest first, so a freed one comes right back
+    assert_eq!(alloc_vector(), Some(vector), "ISA IRQ vector not freed");
+    free_vector(vector);
+    info!("Interrupt self-test passed");
+}
+
+impl Pic {
+    /// Moves the IRQs from the vectors of the exceptions to the ones right behind them.
+    fn remap(&mut self) {
+        // SAFETY: the PICs are always at these ports, if they are present at all
+        unsafe {
+            // ICW1: initialize, ICW4 follows
+            self.command.0.write(0x11);
+            self.command.1.write(0x11);
+            // ICW2: vector offsets
+            self.data.0.write(FIRST_IRQ_VECTOR);
+            self.data.1.write(FIRST_IRQ_VECTOR + 8);
+            // ICW3: the second PIC is connected to IRQ2
+            self.data.0.write(1 << CASCADE_IRQ);
+            self.data.1.write(CASCADE_IRQ);
+            // ICW4: 8086 mode
+            self.data.0.write(0x01);
+            self.data.1.write(0x01);
+        }
+    }
+
+    /// Bit `n` masks IRQ `n`, the cascade is unmasked as long as the second PIC has anything unmasked.
+    fn set_masks(&mut self, masks: u16) {
+        let mut master = masks as u8 | 1 << CASCADE_IRQ;
+        if masks >> 8 != 0xFF {
+            master &= !(1 << CASCADE_IRQ);
+        }
+
+        self.masks = masks;
+        // SAFETY: writing the data ports after initialization only changes the masks
+        unsafe {
+            self.data.0.write(master);
+            self.data.1.write((masks >> 8) as u8);
+        }
+    }
+
+    /// IRQs that are being handled right now.
+    fn in_service(&mut self) -> u16 {
+        // SAFETY: OCW3 selects the in-service register for the next read of the command ports
+        unsafe {
+            self.command.0.write(READ_ISR);
+            self.command.1.write(READ_ISR);
+            (self.command.1.read() as u16) << 8 | self.command.0.read() as u16
+        }
+    }
+
+    /// Whether `irq` was raised although nothing asked for it.
+    ///
+    /// A PIC raises IRQ7 or IRQ15 when an interrupt vanishes before it could be
+    /// delivered. A spurious IRQ15 still took the cascade on the first PIC, which
+    /// gets acknowledged here.
+    fn is_spurious(&mut self, irq: u8) -> bool {
+        if (irq != 7 && irq != 15) || self.in_service() & 1 << irq != 0 {
+            return false;
+        }
+
+        if irq == 15 {
+            // SAFETY: acknowledges the cascade, which is in service
+            unsafe { self.command.0.write(EOI) };
+        }
+        true
+    }
+
+    fn eoi(&mut self, irq: u8) {
+        // SAFETY: acknowledges the interrupt being handled
+        unsafe {
+            if irq >= 8 {
+                self.command.1.write(EOI);
+            }
+            self.command.0.write(EOI);
+        }
+    }
+}
+
+extern "x86-interrupt" fn irq_entry<const VECTOR: u8>(_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+fn dispatch(vector: u8) {
+    let use_pic = USE_PIC.load(Ordering::Acquire);
+    let pic_irq = (vector - FIRST_IRQ_VECTOR < 16).then_some(vector - FIRST_IRQ_VECTOR);
+
+    // spurious interrupts are not in service, there is nothing to acknowledge,
+    // the masked PICs only ever raise spurious ones while the APIC is in use
+    let spurious = match pic_irq {
+        Some(irq) if use_pic => PIC.lock().is_spurious(irq),
+        Some(_) => true,
+        None => vector == SPURIOUS_VECTOR,
+    };
+    if spurious {
+        dispatch::count_spurious(vector);
+        return;
+    }
+
+    if !dispatch::run_handlers(vector) {
+        warn!("Unexpected interrupt on vector {vector}");
+    }
+
+    match pic_irq {
+        Some(irq) => PIC.lock().eoi(irq),
+        None => apic::eoi(),
+    }
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    trace!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+
+    unsafe {
+        dump_stack();
+    }
+}
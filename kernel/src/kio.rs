@@ -1,15 +1,58 @@
-use core::fmt::Write;
+use crate::serial::{EmergencySerial, COM1};
 use crate::FRAME_BUFFER;
-use crate::serial::COM1;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Set while `KernelIo` holds the output locks.
+///
+/// There is only a single CPU, so finding it set on entry means something
+/// interrupted a write. An IRQ handler that logs gets the locks back once the
+/// interrupted write finishes, after an exception or panic they may never be
+/// released. Waiting would deadlock either way, so nested writes take the
+/// emergency path instead.
+static WRITING: AtomicBool = AtomicBool::new(false);
 
 pub struct KernelIo;
 
+/// Output path that never waits on a lock, for panics, exceptions and writes
+/// that interrupted another one.
+///
+/// Serial output bypasses the lock when it is held, so it may land in the middle
+/// of the interrupted line. The framebuffer console is skipped instead, as it
+/// can not be written to concurrently, so that output only shows up on serial.
+pub struct EmergencyIo;
+
 impl Write for KernelIo {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        write!(&*COM1, "{s}")?;
+        if WRITING.swap(true, Ordering::Acquire) {
+            return EmergencyIo.write_str(s);
+        }
+
+        let res = write_locked(s);
+        WRITING.store(false, Ordering::Release);
+
+        res
+    }
+}
+
+fn write_locked(s: &str) -> core::fmt::Result {
+    write!(&*COM1, "{s}")?;
+
+    if let Ok(mut fb) = FRAME_BUFFER.try_get() {
+        fb.write_str(s)?;
+    }
+
+    Ok(())
+}
+
+impl Write for EmergencyIo {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        EmergencySerial.write_str(s)?;
 
-        if let Ok(mut fb) = FRAME_BUFFER.try_get() {
-            fb.write_str(s)?;
+        if let Ok(fb) = FRAME_BUFFER.try_get() {
+            if let Some(res) = fb.try_write_str(s) {
+                res?;
+            }
         }
 
         Ok(())
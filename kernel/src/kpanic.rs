@@ -1,8 +1,21 @@
-use crate::stacktrace::dump_stack;
-use crate::{hlt_loop, println, FRAME_BUFFER};
+use crate::fault::Registers;
+use crate::fb::{PanicScreen, VERTICAL_STRIDE};
+use crate::mem::PageTableDump;
+use crate::serial::{EmergencySerial, RawSerial};
+use crate::stacktrace::walk_stack;
+use crate::{hlt_loop, FRAME_BUFFER};
 use core::fmt::Display;
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const PANIC_COLOR: [u8; 3] = [0x4c, 0x00, 0x99];
+const PANIC_IMAGE: &[u8] = include_bytes!("res/panic.data");
+const PANIC_IMAGE_SIZE: usize = 128;
+const MARGIN: usize = 16;
+
+/// Number of panics that have been entered so far.
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -10,20 +23,84 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 pub fn kernel_panic(printable: impl Display) -> ! {
-    if let Ok(fb) = FRAME_BUFFER.try_get() {
-        // fb.reset();
-        // fb.clear_color([0x4c, 0x00, 0x99]);
-        // fb.draw_rgb4_block(include_bytes!("res/panic.data"), 128, 128);
-    }
+    let regs = Registers::capture();
+    x86_64::instructions::interrupts::disable();
 
-    println!("FATAL ERROR:");
-    println!("------------------------------------");
-    println!("{printable}");
-    println!("------------------------------------");
+    match PANIC_DEPTH.fetch_add(1, Ordering::SeqCst) {
+        0 => (),
+        1 => {
+            // the panic path itself is broken, only use the most basic output there is
+            let _ = write!(RawSerial, "\nPANIC WHILE PANICKING:\n{printable}\n");
+            hlt_loop();
+        }
+        // even printing the nested panic failed, give up
+        _ => hlt_loop(),
+    }
 
+    let mut serial = EmergencySerial;
+    let _ = writeln!(serial, "FATAL ERROR:");
+    let _ = writeln!(serial, "------------------------------------");
+    let _ = writeln!(serial, "{printable}");
+    let _ = writeln!(serial, "------------------------------------");
+    let _ = write!(serial, "CPU state:\n{regs}");
+    let _ = writeln!(serial, "Stack trace:");
     unsafe {
-        dump_stack();
+        walk_stack(|frame| {
+            let _ = writeln!(serial, "    {frame}");
+        });
+    }
+    let _ = write!(serial, "Page tables:\n{PageTableDump}");
+
+    if let Ok(fb) = FRAME_BUFFER.try_get() {
+        // SAFETY: we never return, so whoever held the lock will never continue
+        draw_panic_screen(unsafe { fb.force_lock() }, &printable, &regs);
     }
 
     hlt_loop();
 }
+
+/// Draws the panic image with the message to its right and the CPU state and
+/// stack trace in the lower half of the screen.
+fn draw_panic_screen(mut screen: PanicScreen, printable: &impl Display, regs: &Registers) {
+    let (width, height) = screen.size();
+
+    screen.fill(PANIC_COLOR);
+    screen.draw_rgb(
+        PANIC_IMAGE,
+        PANIC_IMAGE_SIZE,
+        PANIC_IMAGE_SIZE,
+        3,
+        MARGIN,
+        MARGIN,
+    );
+
+    let text_x = 2 * MARGIN + PANIC_IMAGE_SIZE;
+    let lower = (height / 2).max(PANIC_IMAGE_SIZE + 2 * MARGIN);
+
+    let mut message = screen.region(
+        text_x,
+        MARGIN,
+        width.saturating_sub(text_x + MARGIN),
+        lower - 2 * MARGIN,
+    );
+    let _ = write!(message, "KERNEL PANIC\n\n{printable}");
+
+    // header and 7 lines of registers
+    let regs_height = 8 * VERTICAL_STRIDE;
+    let mut state = screen.region(MARGIN, lower, width - 2 * MARGIN, regs_height);
+    let _ = write!(state, "CPU state:\n{regs}");
+
+    let trace_y = lower + regs_height + MARGIN;
+    let mut trace = screen.region(
+        MARGIN,
+        trace_y,
+        width - 2 * MARGIN,
+        height.saturating_sub(trace_y + MARGIN),
+    );
+    let _ = writeln!(trace, "Stack trace:");
+    unsafe {
+        walk_stack(|frame| {
+            let _ = writeln!(trace, "{frame}");
+        });
+    }
+}
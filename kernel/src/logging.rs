@@ -17,7 +17,11 @@ impl Log for KernelLogger {
     }
 
     fn log(&self, record: &Record) {
-        writeln!(KernelIo, "[{}] {}", record.level(), record.args()).unwrap();
+        match crate::time::now() {
+            Some(now) => writeln!(KernelIo, "{now} [{}] {}", record.level(), record.args()),
+            None => writeln!(KernelIo, "[{}] {}", record.level(), record.args()),
+        }
+        .unwrap();
     }
 
     fn flush(&self) {}
@@ -6,54 +6,59 @@
 
 extern crate alloc;
 
+mod acpi;
+mod apic;
 mod fault;
 mod fb;
+mod gdt;
 mod interrupts;
 mod kio;
 mod kpanic;
 mod logging;
 mod mem;
-mod mem2;
 mod rng;
 mod serial;
 mod stacktrace;
+mod time;
 
 use crate::fb::{Float, SharedFrameBuffer};
-use crate::interrupts::init_idt;
+use crate::gdt::{init_gdt, protect_ist_stacks};
+use crate::interrupts::{init_idt, init_pic, InterruptStats};
 use crate::logging::KernelLogger;
-use crate::mem::MemoryManager;
-use crate::serial::COM1;
-use crate::stacktrace::dump_stack;
+use crate::mem::{translate_, KernelImage, MemoryManager, PageTableDump, RegionClass};
+use crate::stacktrace::{init_debug_info, init_symbols};
 use bootloader_api::config::Mapping;
 use bootloader_api::info::Optional;
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
 use conquer_once::spin::OnceCell;
 use core::arch::asm;
 use core::fmt::Write;
-use core::panic::PanicInfo;
 use core::ptr::slice_from_raw_parts;
-use log::info;
-use mem::kalloc::KernelOomHandler;
+use log::{info, warn};
+use mem::kalloc::{init_heap, KernelOomHandler};
 use spinning_top::RawSpinlock;
 use talc::{Talc, Talck};
-use x86_64::instructions::interrupts::int3;
-use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::page_table::PageTableEntry;
-use x86_64::VirtAddr;
+use x86_64::{PhysAddr, VirtAddr};
 
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ( write!(crate::kio::KernelIo, $($arg)*).unwrap() );
+    ($($arg:tt)*) => ( write!($crate::kio::KernelIo, $($arg)*).unwrap() );
 }
 
 #[macro_export]
 macro_rules! println {
-    () => { writeln!(crate::kio::KernelIo).unwrap() };
-    ($($arg:tt)*) => ( core::writeln!(crate::kio::KernelIo, $($arg)*).unwrap() );
+    () => { writeln!($crate::kio::KernelIo).unwrap() };
+    ($($arg:tt)*) => ( core::writeln!($crate::kio::KernelIo, $($arg)*).unwrap() );
 }
 
+#[cfg_attr(not(feature = "heap-debug"), global_allocator)]
+static ALLOCATOR: Talck<RawSpinlock, KernelOomHandler> =
+    Talc::new(KernelOomHandler::uninit()).lock();
+
+/// Checks every allocation before handing it on to `ALLOCATOR`.
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: Talck<RawSpinlock, KernelOomHandler> = Talc::new(KernelOomHandler {}).lock();
+static DEBUG_ALLOCATOR: mem::DebugAllocator = mem::DebugAllocator::new();
 
 static FRAME_BUFFER: OnceCell<SharedFrameBuffer> = OnceCell::uninit();
 
@@ -77,7 +82,7 @@ fn entry(_info: &'static mut BootInfo) -> ! {
             "xor rbp, rbp",
             "call {km}",
             "ud2",
-            stack_end = in(reg) &mut STACK_END,
+            stack_end = in(reg) &raw mut STACK_END,
             km = sym kernel_main,
             options(noreturn)
         )
@@ -89,18 +94,43 @@ extern "C" fn kernel_main(
         framebuffer,
         memory_regions,
         physical_memory_offset,
+        ramdisk_addr,
+        ramdisk_len,
+        kernel_addr,
+        kernel_len,
+        kernel_image_offset,
+        rsdp_addr,
         ..
     }: &'static mut BootInfo,
 ) -> ! {
     KernelLogger::init();
 
+    let fb_range = match &*framebuffer {
+        Optional::Some(fb) => {
+            let start = VirtAddr::from_ptr(fb.buffer().as_ptr());
+            Some(start..start + fb.info().byte_len as u64)
+        }
+        Optional::None => None,
+    };
+
     if let Optional::Some(fb) = framebuffer {
         FRAME_BUFFER.init_once(move || SharedFrameBuffer::new(fb));
     };
     FRAME_BUFFER.try_get().unwrap().clear();
 
+    init_gdt();
     init_idt();
 
+    if let Optional::Some(addr) = ramdisk_addr {
+        // SAFETY: the bootloader mapped the ramdisk for us and we never unmap it
+        unsafe {
+            init_symbols(
+                &*slice_from_raw_parts(*addr as *const u8, *ramdisk_len as usize),
+                *kernel_image_offset,
+            );
+        }
+    }
+
     println!();
     FRAME_BUFFER.try_get().unwrap().draw_rgb_block(
         include_bytes!("res/logo2.data"),
@@ -114,32 +144,77 @@ extern "C" fn kernel_main(
 
     println!("Starting dergOs...");
 
+    let phys_offset = VirtAddr::new(
+        physical_memory_offset
+            .into_option()
+            .expect("physical memory offset must be configured"),
+    );
+
+    // SAFETY: the bootloader keeps the kernel file in memory marked as in use
+    let kernel = KernelImage::new(
+        unsafe {
+            &*slice_from_raw_parts(
+                translate_::<u8>(phys_offset, PhysAddr::new(*kernel_addr)),
+                *kernel_len as usize,
+            )
+        },
+        *kernel_image_offset,
+    );
+
     // SAFETY: We trust that the information provided by BootInfo are correct.
     //         By moving them to the memory manager we prevent further modifications.
-    let _mem_mng = unsafe {
-        MemoryManager::new(
-            VirtAddr::new(
-                physical_memory_offset
-                    .into_option()
-                    .expect("physical memory offset must be configured"),
-            ),
-            memory_regions,
-        )
+    let mem_mng = unsafe { MemoryManager::new(phys_offset, memory_regions, kernel, fb_range) };
+    info!("Memory map:\n{}", mem_mng.memory_map());
+
+    // SAFETY: the kernel file stays around forever
+    unsafe { init_debug_info(kernel.elf(), kernel.offset()) };
+
+    protect_ist_stacks(mem_mng);
+    init_heap(mem_mng);
+    mem_mng.self_test();
+
+    let kernel_file = PhysAddr::new(*kernel_addr)..PhysAddr::new(*kernel_addr + *kernel_len);
+    // SAFETY: the bootloader is done, what we still use of its memory is mapped or the kernel
+    //         file, which stays around for its debug info
+    unsafe { mem_mng.reclaim(RegionClass::Bootloader, &[kernel_file]) };
+
+    let acpi = rsdp_addr.into_option().and_then(|rsdp| {
+        // SAFETY: the bootloader hands over the RSDP of the firmware
+        unsafe { acpi::init(mem_mng, PhysAddr::new(rsdp)) }
+    });
+    let apic = match acpi.and_then(|acpi| acpi.madt.as_ref()) {
+        // SAFETY: the MADT describes this machine
+        Some(madt) if apic::has_apic() => match unsafe { apic::init(mem_mng, madt) } {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to set up the APIC: {err}");
+                false
+            }
+        },
+        _ => false,
     };
+    if !apic {
+        warn!("No APIC found, falling back to the legacy PICs");
+        init_pic();
+    }
+    // SAFETY: the HPET table describes this machine
+    unsafe { time::init(mem_mng, acpi.and_then(|acpi| acpi.hpet.as_ref())) };
+    // SAFETY: everything the kernel needs from the ACPI tables has been copied
+    unsafe { mem_mng.reclaim(RegionClass::AcpiReclaimable, &[]) };
 
-    let (lv4pt, _) = Cr3::read();
+    x86_64::instructions::interrupts::enable();
+    interrupts::self_test();
+    time::self_test();
 
-    println!("0x{:X}", lv4pt.start_address().as_u64());
+    info!("Memory usage:\n{}", mem_mng.usage());
+    info!("Interrupts:\n{}", InterruptStats);
 
-    let addr = (*physical_memory_offset.as_ref().unwrap() + lv4pt.start_address().as_u64())
-        as *const PageTableEntry;
-    let pt = unsafe { &*slice_from_raw_parts(addr, 512) };
+    #[cfg(feature = "frame-bench")]
+    info!("Frame allocator: {}", mem_mng.benchmark_frames(100_000));
 
-    for pte in pt {
-        println!("{pte:?}");
-    }
+    println!("{}", PageTableDump);
 
-    hlt_loop()
+    time::idle_loop()
 }
 
 pub fn hlt_loop() -> ! {
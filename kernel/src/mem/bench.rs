@@ -0,0 +1,98 @@
+use crate::mem::kfalloc::KernelFrameAllocator;
+use crate::mem::MemoryManager;
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{Display, Formatter};
+use x86_64::structures::paging::frame::PhysFrameRange;
+
+/// Allocations kept alive at the same time.
+const SLOTS: usize = 256;
+
+/// Cycles the frame allocator spent on a mixed workload, see `benchmark_frames`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameBenchmark {
+    pub allocs: u64,
+    pub alloc_cycles: u64,
+    pub frees: u64,
+    pub free_cycles: u64,
+    pub failed: u64,
+}
+
+impl MemoryManager {
+    /// Times `rounds` allocations and frees of 1 to 8 frames in a pseudo random order,
+    /// to compare the frame allocator's backends.
+    ///
+    /// Every frame is given back before it returns.
+    pub fn benchmark_frames(&self, rounds: usize) -> FrameBenchmark {
+        let allocator = self.inner.lock().allocator;
+        let mut slots: [Option<PhysFrameRange>; SLOTS] = [None; SLOTS];
+        let mut result = FrameBenchmark::default();
+
+        // xorshift, the same sequence on every run
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..rounds {
+            let random = next();
+            let slot = &mut slots[random as usize % SLOTS];
+
+            match slot.take() {
+                Some(frames) => free(allocator, frames, &mut result),
+                None => {
+                    let count = 1 << ((random >> 32) % 4);
+
+                    let start = rdtsc();
+                    *slot = allocator.allocate_contiguous(count);
+                    result.alloc_cycles += rdtsc() - start;
+
+                    match slot {
+                        Some(_) => result.allocs += 1,
+                        None => result.failed += 1,
+                    }
+                }
+            }
+        }
+
+        for frames in slots.into_iter().flatten() {
+            free(allocator, frames, &mut result);
+        }
+
+        result
+    }
+}
+
+fn free(allocator: &KernelFrameAllocator, frames: PhysFrameRange, result: &mut FrameBenchmark) {
+    let start = rdtsc();
+    // SAFETY: the frames were allocated by the benchmark and never used
+    unsafe { allocator.deallocate_range(frames) };
+    result.free_cycles += rdtsc() - start;
+    result.frees += 1;
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: reading the time stamp counter has no side effects
+    unsafe { _rdtsc() }
+}
+
+impl Display for FrameBenchmark {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} backend: {} allocs at {} cycles, {} frees at {} cycles",
+            KernelFrameAllocator::BACKEND,
+            self.allocs,
+            self.alloc_cycles / self.allocs.max(1),
+            self.frees,
+            self.free_cycles / self.frees.max(1),
+        )?;
+
+        if self.failed > 0 {
+            write!(f, ", {} allocs failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
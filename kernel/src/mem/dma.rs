@@ -0,0 +1,69 @@
+use crate::mem::kfalloc::{Zone, ZoneStats};
+use crate::mem::{frame_allocator, MemoryManager};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physically contiguous memory for a device, given back to the frame allocator when dropped.
+///
+/// It is accessed through the physical memory window, so the CPU caches it.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frames: PhysFrameRange,
+    virt: VirtAddr,
+}
+
+impl DmaBuffer {
+    /// Address to program into the device.
+    pub fn phys(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> u64 {
+        (self.frames.end - self.frames.start) * 4096
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let allocator = frame_allocator().expect("dma buffers come from the frame allocator");
+
+        // SAFETY: the buffer owned the frames, the device has to be done with them by now
+        unsafe { allocator.deallocate_range(self.frames) };
+    }
+}
+
+impl MemoryManager {
+    /// Allocates `count` zeroed, physically contiguous frames in `zone` or below,
+    /// starting at a multiple of `align` bytes.
+    ///
+    /// Aligning to the buffer size rounded up to a power of two keeps it from
+    /// crossing a boundary of that size, like the 64KiB ones of ISA DMA.
+    pub fn alloc_dma(&self, count: usize, align: usize, zone: Zone) -> Option<DmaBuffer> {
+        let allocator = self.inner.lock().allocator;
+        let frames = allocator.allocate_in(count, align, zone)?;
+        let virt = self.phys_offset + frames.start.start_address().as_u64();
+
+        let buffer = DmaBuffer { frames, virt };
+        // SAFETY: the frames were just allocated and the window maps them
+        unsafe {
+            buffer
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, buffer.size() as usize)
+        };
+
+        Some(buffer)
+    }
+
+    pub fn zone_stats(&self) -> [ZoneStats; Zone::ALL.len()] {
+        self.inner.lock().allocator.zone_stats()
+    }
+}
@@ -0,0 +1,343 @@
+use crate::kpanic::kernel_panic;
+use crate::mem::{MapFlags, MemoryManager};
+use crate::stacktrace::Backtrace;
+use crate::ALLOCATOR;
+use conquer_once::spin::OnceCell;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::{Display, Formatter};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use log::info;
+use spinning_top::Spinlock;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+/// Fresh allocations get filled with this, to make reads of uninitialized memory stand out.
+const ALLOC_POISON: u8 = 0xCD;
+/// Freed allocations get filled with this, any change means it was written after free.
+const FREE_POISON: u8 = 0xDD;
+/// Surrounds every allocation, any change means it was written out of bounds.
+const REDZONE_FILL: u8 = 0xFD;
+const REDZONE: usize = 16;
+
+const ALLOCATED: u64 = 0xA110_CA7E_DA11_0C8D;
+const FREED: u64 = 0xF4EE_DF4E_EDF4_EEDD;
+
+/// Freed allocations held back before they can be reused, so that writes after
+/// free and double frees can be caught for a while.
+const QUARANTINE: usize = 256;
+
+/// Whether allocations get pages of their own once the heap is set up.
+const GUARD_PAGES: bool = cfg!(feature = "heap-guard-pages");
+
+static MEM: OnceCell<&'static MemoryManager> = OnceCell::uninit();
+
+/// Global allocator checking every heap allocation, enabled by the `heap-debug` feature.
+///
+/// Allocations come from the kernel heap with redzones around them and get poisoned on
+/// allocation and free. Freed allocations go into a quarantine before the heap gets them
+/// back, whatever touched them by then gets reported. With the `heap-guard-pages` feature
+/// every allocation gets pages of its own instead, placed right in front of an unmapped
+/// page so overflows fault immediately.
+///
+/// Violations are reported through `kernel_panic`, with the backtrace of the allocation.
+/// Guard pages go through the `MemoryManager`, which therefore must not free heap memory
+/// while holding its locks.
+pub struct DebugAllocator {
+    quarantine: Spinlock<Quarantine>,
+}
+
+struct Quarantine {
+    ptrs: [usize; QUARANTINE],
+    next: usize,
+}
+
+/// Stored right in front of the front redzone of every allocation.
+#[repr(C)]
+struct Header {
+    state: u64,
+    size: usize,
+    align: usize,
+    /// Bytes from the start of the underlying block to the data.
+    offset: usize,
+    /// Bytes of redzone behind the data.
+    rear: usize,
+    /// Pages mapped for the allocation, 0 if it came from the heap.
+    pages: usize,
+    alloc: Backtrace,
+    free: Backtrace,
+}
+
+struct Violation<'a> {
+    kind: &'static str,
+    ptr: *mut u8,
+    header: Option<&'a Header>,
+}
+
+impl DebugAllocator {
+    pub const fn new() -> Self {
+        Self {
+            quarantine: Spinlock::new(Quarantine {
+                ptrs: [0; QUARANTINE],
+                next: 0,
+            }),
+        }
+    }
+
+    /// Lets the allocator map pages of its own, once the heap is set up.
+    pub fn init(mem: &'static MemoryManager) {
+        MEM.init_once(|| mem);
+
+        info!(
+            "Heap debugging enabled, {}",
+            if GUARD_PAGES {
+                "every allocation gets its own pages"
+            } else {
+                "allocations are surrounded by redzones"
+            }
+        );
+    }
+}
+
+// SAFETY: every allocation is carved from its own heap block or pages
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // SAFETY: the kernel is built with frame pointers
+        let trace = unsafe { Backtrace::capture() };
+
+        let ptr = match MEM.try_get() {
+            Ok(mem) if GUARD_PAGES => alloc_pages(mem, layout),
+            _ => alloc_heap(layout),
+        };
+        let Some((ptr, mut header)) = ptr else {
+            return null_mut();
+        };
+
+        header.alloc = trace;
+        let rear = header.rear;
+        // SAFETY: the header and both redzones belong to the allocation we just made
+        unsafe {
+            header_ptr(ptr).write(header);
+            ptr.sub(REDZONE).write_bytes(REDZONE_FILL, REDZONE);
+            ptr.add(layout.size()).write_bytes(REDZONE_FILL, rear);
+            ptr.write_bytes(ALLOC_POISON, layout.size());
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Ok(mem) = MEM.try_get() {
+            // freed guarded allocations get unmapped when they leave the quarantine
+            if GUARD_PAGES && mem.query(VirtAddr::from_ptr(header_ptr(ptr))).is_none() {
+                report(
+                    "free of an unmapped pointer, maybe a double free",
+                    ptr,
+                    None,
+                );
+            }
+        }
+
+        // SAFETY: the caller guarantees that `ptr` came from `alloc`
+        let header = unsafe { &mut *header_ptr(ptr) };
+        match header.state {
+            ALLOCATED => (),
+            FREED => report("double free", ptr, Some(&*header)),
+            _ => report("free of an unknown pointer or corrupted header", ptr, None),
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            report("freed with a different layout", ptr, Some(&*header));
+        }
+        // SAFETY: the redzones belong to the allocation
+        if unsafe { !redzones_intact(ptr, header) } {
+            report("out of bounds write", ptr, Some(&*header));
+        }
+
+        header.state = FREED;
+        // SAFETY: the kernel is built with frame pointers
+        header.free = unsafe { Backtrace::capture() };
+        // SAFETY: the allocation belongs to us again
+        unsafe { ptr.write_bytes(FREE_POISON, header.size) };
+
+        if header.pages > 0 {
+            let mem = MEM
+                .try_get()
+                .expect("guarded allocations need the memory manager");
+            let data = pages_of(ptr, header);
+            // SAFETY: only the allocation lives in these pages, reads still work after this
+            unsafe { mem.protect(data, MapFlags::RODATA) }
+                .expect("guarded allocations are mapped with 4KiB pages");
+        }
+
+        let evicted = self.quarantine.lock().push(ptr as usize);
+        if evicted != 0 {
+            // SAFETY: nobody may use allocations after freeing them
+            unsafe { release(evicted as *mut u8) };
+        }
+    }
+}
+
+impl Quarantine {
+    /// Adds `ptr` and returns the oldest entry it replaced, 0 if there was none.
+    fn push(&mut self, ptr: usize) -> usize {
+        let evicted = core::mem::replace(&mut self.ptrs[self.next], ptr);
+        self.next = (self.next + 1) % QUARANTINE;
+        evicted
+    }
+}
+
+/// Allocates from the kernel heap, with room for the header and both redzones.
+fn alloc_heap(layout: Layout) -> Option<(*mut u8, Header)> {
+    let align = layout.align().max(align_of_header());
+    let offset = (size_of::<Header>() + REDZONE).next_multiple_of(align);
+    let size = offset.checked_add(layout.size())?.checked_add(REDZONE)?;
+
+    let block = Layout::from_size_align(size, align).ok()?;
+    // SAFETY: the block is never empty
+    let start = unsafe { ALLOCATOR.alloc(block) };
+    if start.is_null() {
+        return None;
+    }
+
+    // SAFETY: the block has room for the offset
+    let ptr = unsafe { start.add(offset) };
+    Some((ptr, new_header(layout, offset, REDZONE, 0)))
+}
+
+/// Maps pages for the allocation alone, with the data ending right in front of an
+/// unmapped page. The page in front of them stays unmapped as well.
+fn alloc_pages(mem: &MemoryManager, layout: Layout) -> Option<(*mut u8, Header)> {
+    let align = layout.align().max(align_of_header());
+    let front = size_of::<Header>() + REDZONE;
+    let needed = front + layout.size() + align - 1;
+    let count = needed.div_ceil(4096);
+
+    let range = mem.reserve_virtual(count as u64 + 2, 4096)?;
+    let data = Page::range(range.start + 1, range.end - 1);
+
+    if mem.map_anonymous(data, MapFlags::DATA).is_err() {
+        // SAFETY: nothing got mapped
+        unsafe { mem.release_virtual(range) };
+        return None;
+    }
+
+    let start = data.start.start_address().as_u64() as usize;
+    let end = data.end.start_address().as_u64() as usize;
+    let data_start = (end - layout.size()) & !(align - 1);
+
+    let header = new_header(
+        layout,
+        data_start - start,
+        end - data_start - layout.size(),
+        count,
+    );
+    Some((data_start as *mut u8, header))
+}
+
+/// Gives an allocation leaving the quarantine back, after checking nobody touched it.
+///
+/// # Safety
+/// `ptr` has to be a freed allocation of the debug allocator.
+unsafe fn release(ptr: *mut u8) {
+    // SAFETY: guaranteed by the caller, headers of quarantined allocations stay readable
+    let header = unsafe { &*header_ptr(ptr) };
+
+    // SAFETY: same as above
+    let data = unsafe { core::slice::from_raw_parts(ptr, header.size) };
+    if header.state != FREED || data.iter().any(|&byte| byte != FREE_POISON) {
+        report("write after free", ptr, Some(header));
+    }
+    // SAFETY: same as above
+    if unsafe { !redzones_intact(ptr, header) } {
+        report("out of bounds write after free", ptr, Some(header));
+    }
+
+    if header.pages > 0 {
+        let mem = MEM
+            .try_get()
+            .expect("guarded allocations need the memory manager");
+        let data = pages_of(ptr, header);
+        // SAFETY: the allocation is gone, its address space stays reserved so
+        //         accesses through dangling pointers fault
+        unsafe { mem.unmap_anonymous(data) }
+            .expect("guarded allocations are mapped with 4KiB pages");
+    } else {
+        let size = header.offset + header.size + header.rear;
+        let align = header.align.max(align_of_header());
+        // SAFETY: the block was allocated with this layout by `alloc_heap`
+        unsafe {
+            ALLOCATOR.dealloc(
+                ptr.sub(header.offset),
+                Layout::from_size_align_unchecked(size, align),
+            )
+        };
+    }
+}
+
+/// # Safety
+/// `ptr` has to be an allocation of the debug allocator.
+unsafe fn redzones_intact(ptr: *mut u8, header: &Header) -> bool {
+    // SAFETY: guaranteed by the caller
+    let (front, rear) = unsafe {
+        (
+            core::slice::from_raw_parts(ptr.sub(REDZONE), REDZONE),
+            core::slice::from_raw_parts(ptr.add(header.size), header.rear),
+        )
+    };
+
+    front.iter().chain(rear).all(|&byte| byte == REDZONE_FILL)
+}
+
+fn new_header(layout: Layout, offset: usize, rear: usize, pages: usize) -> Header {
+    Header {
+        state: ALLOCATED,
+        size: layout.size(),
+        align: layout.align(),
+        offset,
+        rear,
+        pages,
+        alloc: Backtrace::empty(),
+        free: Backtrace::empty(),
+    }
+}
+
+fn header_ptr(ptr: *mut u8) -> *mut Header {
+    ptr.wrapping_sub(REDZONE + size_of::<Header>()).cast()
+}
+
+const fn align_of_header() -> usize {
+    core::mem::align_of::<Header>()
+}
+
+fn pages_of(ptr: *mut u8, header: &Header) -> PageRange {
+    let start = Page::containing_address(VirtAddr::from_ptr(ptr) - header.offset as u64);
+    Page::range(start, start + header.pages as u64)
+}
+
+fn report(kind: &'static str, ptr: *mut u8, header: Option<&Header>) -> ! {
+    kernel_panic(Violation { kind, ptr, header })
+}
+
+impl Display for Violation<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Heap corruption: {} at {:p}", self.kind, self.ptr)?;
+
+        let Some(header) = self.header else {
+            return Ok(());
+        };
+
+        writeln!(
+            f,
+            "Allocation of {} bytes, aligned to {}",
+            header.size, header.align
+        )?;
+        write!(f, "Allocated at:\n{}", header.alloc)?;
+        if header.state == FREED {
+            write!(f, "Freed at:\n{}", header.free)?;
+        }
+
+        Ok(())
+    }
+}
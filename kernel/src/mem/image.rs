@@ -0,0 +1,100 @@
+use core::ops::Range;
+use x86_64::VirtAddr;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The kernel's own ELF file, as loaded by the bootloader.
+#[derive(Copy, Clone)]
+pub struct KernelImage {
+    elf: &'static [u8],
+    offset: u64,
+}
+
+/// A loadable segment of the kernel.
+#[derive(Copy, Clone, Debug)]
+pub struct Segment {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: u32,
+}
+
+impl KernelImage {
+    /// `elf` is the kernel file, `offset` where its segments got loaded.
+    pub fn new(elf: &'static [u8], offset: u64) -> Self {
+        Self { elf, offset }
+    }
+
+    pub fn elf(&self) -> &'static [u8] {
+        self.elf
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Loadable segments at their final addresses.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> {
+        let elf = self.elf;
+        let offset = self.offset;
+
+        let phoff = u64_at(elf, 0x20).unwrap_or(0) as usize;
+        let phentsize = u16_at(elf, 0x36).unwrap_or(0) as usize;
+        let phnum = if elf.starts_with(b"\x7fELF") {
+            u16_at(elf, 0x38).unwrap_or(0) as usize
+        } else {
+            0
+        };
+
+        (0..phnum).filter_map(move |index| {
+            let base = phoff + index * phentsize;
+            if u32_at(elf, base)? != PT_LOAD {
+                return None;
+            }
+
+            Some(Segment {
+                start: VirtAddr::new(offset + u64_at(elf, base + 0x10)?),
+                size: u64_at(elf, base + 0x28)?,
+                flags: u32_at(elf, base + 4)?,
+            })
+        })
+    }
+
+    /// Virtual range covered by all segments.
+    pub fn span(&self) -> Range<VirtAddr> {
+        let start = self.segments().map(|seg| seg.start).min();
+        let end = self.segments().map(|seg| seg.start + seg.size).max();
+
+        match (start, end) {
+            (Some(start), Some(end)) => start..end,
+            _ => VirtAddr::new(self.offset)..VirtAddr::new(self.offset),
+        }
+    }
+}
+
+impl Segment {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
@@ -0,0 +1,201 @@
+use crate::mem::{phys_offset, MapFlags};
+use core::fmt::{Display, Formatter};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Mappings that are contiguous both virtually and physically and share page size and flags.
+#[derive(Copy, Clone, Debug)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub page_size: u64,
+    /// Effective flags, taking every level of the hierarchy into account.
+    pub flags: MapFlags,
+}
+
+/// Listing of everything mapped by the active page tables.
+pub struct PageTableDump;
+
+/// Access rights granted by the entries above the one currently looked at.
+#[derive(Copy, Clone)]
+struct Inherited {
+    writable: bool,
+    no_execute: bool,
+    user: bool,
+}
+
+/// Calls `f` for every mapped range of the active page tables, in ascending order.
+///
+/// The tables behind CR3 are read directly without taking any lock, so this can be
+/// used while panicking as well.
+pub fn for_each_mapping(mut f: impl FnMut(&MappedRange)) {
+    let Some(offset) = phys_offset() else {
+        return;
+    };
+
+    let root = Cr3::read().0.start_address();
+    let inherited = Inherited {
+        writable: true,
+        no_execute: false,
+        user: true,
+    };
+
+    let mut current: Option<MappedRange> = None;
+    let mut merge = |leaf: MappedRange| match &mut current {
+        Some(run) if run.continues_with(&leaf) => run.size += leaf.size,
+        _ => {
+            if let Some(run) = current.replace(leaf) {
+                f(&run);
+            }
+        }
+    };
+    walk(offset, root, 4, 0, inherited, &mut merge);
+
+    if let Some(run) = current {
+        f(&run);
+    }
+}
+
+/// Number of page tables reachable from CR3, including the level 4 table itself.
+pub fn count_page_tables() -> usize {
+    let mut count = 0;
+    for_each_page_table(|_| count += 1);
+    count
+}
+
+/// Calls `f` with the address of every page table reachable from CR3.
+pub fn for_each_page_table(mut f: impl FnMut(PhysAddr)) {
+    let Some(offset) = phys_offset() else {
+        return;
+    };
+
+    walk_tables(offset, Cr3::read().0.start_address(), 4, &mut f);
+}
+
+fn walk_tables(offset: VirtAddr, table: PhysAddr, level: u32, f: &mut dyn FnMut(PhysAddr)) {
+    f(table);
+    if level == 1 {
+        return;
+    }
+
+    // SAFETY: same as in `walk`
+    let table = unsafe { &*(offset + table.as_u64()).as_ptr::<PageTable>() };
+
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT)
+            && (level == 4 || !flags.contains(PageTableFlags::HUGE_PAGE))
+        {
+            walk_tables(offset, entry.addr(), level - 1, f);
+        }
+    }
+}
+
+fn walk(
+    offset: VirtAddr,
+    table: PhysAddr,
+    level: u32,
+    base: u64,
+    inherited: Inherited,
+    f: &mut dyn FnMut(MappedRange),
+) {
+    // SAFETY: the physical memory offset maps all of physical memory and every
+    //         table we reach was referenced by a present entry
+    let table = unsafe { &*(offset + table.as_u64()).as_ptr::<PageTable>() };
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let start = base + index as u64 * entry_size;
+        let inherited = Inherited {
+            writable: inherited.writable && flags.contains(PageTableFlags::WRITABLE),
+            no_execute: inherited.no_execute || flags.contains(PageTableFlags::NO_EXECUTE),
+            user: inherited.user && flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        };
+
+        if level == 1 || (level < 4 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            f(MappedRange {
+                start: VirtAddr::new_truncate(start),
+                phys: entry.addr(),
+                size: entry_size,
+                page_size: entry_size,
+                flags: MapFlags {
+                    writable: inherited.writable,
+                    no_execute: inherited.no_execute,
+                    user: inherited.user,
+                    ..MapFlags::from_pte(flags)
+                },
+            });
+        } else {
+            walk(offset, entry.addr(), level - 1, start, inherited, f);
+        }
+    }
+}
+
+impl MappedRange {
+    pub fn end(&self) -> VirtAddr {
+        // the last range may end exactly at the top of the address space
+        VirtAddr::new_truncate(self.start.as_u64().wrapping_add(self.size))
+    }
+
+    fn continues_with(&self, next: &MappedRange) -> bool {
+        self.page_size == next.page_size
+            && self.flags == next.flags
+            && self.start.as_u64().wrapping_add(self.size) == next.start.as_u64()
+            && self.phys.as_u64() + self.size == next.phys.as_u64()
+    }
+}
+
+impl Display for MappedRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#013x} {} {} {}",
+            self.start,
+            self.end(),
+            self.phys,
+            Size(self.size),
+            match self.page_size {
+                0x1000 => "4K",
+                0x20_0000 => "2M",
+                _ => "1G",
+            },
+            self.flags
+        )
+    }
+}
+
+impl Display for PageTableDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut result = Ok(());
+        for_each_mapping(|range| {
+            if result.is_ok() {
+                result = writeln!(f, "{range}");
+            }
+        });
+
+        result
+    }
+}
+
+/// Byte count in the largest unit that represents it exactly.
+pub(super) struct Size(pub u64);
+
+impl Display for Size {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let units = [(40, "T"), (30, "G"), (20, "M"), (10, "K")];
+
+        let (shift, unit) = units
+            .into_iter()
+            .find(|&(shift, _)| self.0 >= 1 << shift && self.0.is_multiple_of(1 << shift))
+            .unwrap_or((0, "B"));
+
+        write!(f, "{:>5}{unit}", self.0 >> shift)
+    }
+}
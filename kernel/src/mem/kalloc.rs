@@ -1,10 +1,132 @@
+use crate::mem::{MapFlags, MemoryManager};
+use crate::ALLOCATOR;
 use core::alloc::Layout;
-use talc::{OomHandler, Talc};
+use log::{debug, warn};
+use talc::{OomHandler, Span, Talc};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
 
-pub struct KernelOomHandler {}
+/// Memory mapped for the heap right away.
+const INITIAL_HEAP_SIZE: usize = 1024 * 1024;
+
+/// The heap never grows by less than this, so we don't end up here for every allocation.
+const MIN_GROWTH: usize = 256 * 1024;
+
+/// Size of the virtual range reserved for the heap.
+const MAX_HEAP_SIZE: usize = 64 * 1024 * 1024 * 1024;
+
+/// Grows the heap by mapping more frames behind its end.
+pub struct KernelOomHandler {
+    mem: Option<&'static MemoryManager>,
+
+    /// Memory claimed by talc so far.
+    heap: Span,
+    /// End of the mapped part of the heap.
+    mapped: usize,
+    /// End of the virtual range reserved for the heap.
+    limit: usize,
+}
+
+impl KernelOomHandler {
+    /// Handler that refuses to grow, until `init_heap` sets it up.
+    pub const fn uninit() -> Self {
+        Self {
+            mem: None,
+            heap: Span::empty(),
+            mapped: 0,
+            limit: 0,
+        }
+    }
+}
 
 impl OomHandler for KernelOomHandler {
-    fn handle_oom(_talc: &mut Talc<Self>, _layout: Layout) -> Result<(), ()> {
-        Err(())
+    fn handle_oom(talc: &mut Talc<Self>, layout: Layout) -> Result<(), ()> {
+        let this = &talc.oom_handler;
+        let mem = this.mem.ok_or(())?;
+
+        // leave some room for talc's own metadata
+        let grow_by = (layout.size() + layout.align() + 64)
+            .max(MIN_GROWTH)
+            .next_multiple_of(4096);
+
+        let start = this.mapped;
+        let end = match start.checked_add(grow_by) {
+            Some(end) if end <= this.limit => end,
+            _ => {
+                warn!("Kernel heap exhausted, failed to allocate {layout:?}");
+                return Err(());
+            }
+        };
+
+        if let Err(err) = mem.map_anonymous(pages(start, end), MapFlags::DATA) {
+            warn!("Failed to grow the kernel heap: {err:?}");
+            return Err(());
+        }
+
+        let old = this.heap;
+        let base = old.get_base_acme().ok_or(())?.0;
+
+        // SAFETY: the new pages directly follow the heap and are mapped now
+        let heap = unsafe { talc.extend(old, Span::new(base, end as *mut u8)) };
+
+        talc.oom_handler.heap = heap;
+        talc.oom_handler.mapped = end;
+
+        debug!("Kernel heap grown to {} KiB", heap.size() / 1024);
+        Ok(())
     }
 }
+
+/// Reserves the kernel heap and maps its first pages.
+///
+/// Before this, every allocation fails.
+pub fn init_heap(mem: &'static MemoryManager) {
+    let start = mem
+        .reserve_virtual((MAX_HEAP_SIZE / 4096) as u64, 4096)
+        .expect("no free virtual memory for the kernel heap")
+        .start
+        .start_address()
+        .as_u64() as usize;
+    let end = start + INITIAL_HEAP_SIZE;
+
+    mem.map_anonymous(pages(start, end), MapFlags::DATA)
+        .expect("failed to map the initial kernel heap");
+
+    let mut talc = ALLOCATOR.lock();
+
+    // SAFETY: the memory was just mapped and belongs to nobody else
+    let heap = unsafe { talc.claim(Span::new(start as *mut u8, end as *mut u8)) }
+        .expect("failed to claim the initial kernel heap");
+
+    talc.oom_handler = KernelOomHandler {
+        mem: Some(mem),
+        heap,
+        mapped: end,
+        limit: start + MAX_HEAP_SIZE,
+    };
+
+    debug!("Kernel heap at 0x{start:X}, {} KiB", heap.size() / 1024);
+    drop(talc);
+
+    #[cfg(feature = "heap-debug")]
+    crate::mem::DebugAllocator::init(mem);
+}
+
+/// Bytes of the kernel heap that are backed by frames.
+pub fn heap_size() -> usize {
+    let talc = ALLOCATOR.lock();
+    let handler = &talc.oom_handler;
+
+    handler
+        .heap
+        .get_base_acme()
+        .map_or(0, |(base, _)| handler.mapped - base as usize)
+}
+
+fn pages(start: usize, end: usize) -> PageRange {
+    Page::range(
+        Page::containing_address(VirtAddr::new(start as u64)),
+        Page::containing_address(VirtAddr::new(end as u64)),
+    )
+}
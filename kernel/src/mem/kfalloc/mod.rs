@@ -1,51 +1,93 @@
-mod lla;
-
-use crate::mem::kfalloc::lla::{AlignedNodePage, NodeTraverser, PageNode};
-use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
-use core::mem::{forget, ManuallyDrop};
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::mem::forget;
 use core::ops::Range;
 
-use core::ptr::{write_volatile, NonNull};
-use log::{trace, warn};
-use spinning_top::lock_api::MutexGuard;
-use spinning_top::{RawSpinlock, Spinlock};
-
-use x86_64::structures::paging::{FrameAllocator, Page, PageSize, PhysFrame, Size4KiB};
+use core::ptr::NonNull;
+use log::{debug, trace, warn};
+#[cfg(feature = "buddy-allocator")]
+use page_list::BuddyAllocator;
+#[cfg(not(feature = "buddy-allocator"))]
+use page_list::FreeList;
+use page_list::{whole_pages, CombinedRegions, FreeRange, PhysWindow, PAGE_SIZE};
+use spinning_top::Spinlock;
+
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
+mod zone;
+
+pub use zone::Zone;
+
 static_assertions::const_assert!(core::mem::size_of::<KernelFrameAllocator>() <= 4096);
 
 #[repr(align(4096))]
 pub struct KernelFrameAllocator {
-    phys_offset: VirtAddr,
-
     inner: Spinlock<InnerAllocator>,
 }
 
 struct InnerAllocator {
-    start: Option<NonNull<AlignedNodePage>>,
+    /// Indexed by `Zone::index`.
+    zones: [ZoneFrames; Zone::ALL.len()],
+    /// Frames handed out through a `PageRangeLease`.
+    leased: usize,
 }
 
-struct PageReservingIter {
-    kfa: &'static KernelFrameAllocator,
+/// Frames of a single zone.
+struct ZoneFrames {
+    free: Backend,
+    total: usize,
+}
 
-    left: usize,
+/// Keeps track of the free frames of a zone, selected by the `buddy-allocator` feature.
+#[cfg(not(feature = "buddy-allocator"))]
+type Backend = FreeList<OffsetWindow>;
+#[cfg(feature = "buddy-allocator")]
+type Backend = BuddyAllocator<OffsetWindow>;
+
+/// All of physical memory, as mapped by the bootloader at an offset.
+#[derive(Copy, Clone)]
+struct OffsetWindow(VirtAddr);
+
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    /// Part of `used` held by leases, including kept ones.
+    pub leased: usize,
 }
 
-struct RegionCombiningIter<I> {
-    inner: I,
-    current: Option<MemoryRegion>,
+#[derive(Copy, Clone, Debug)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    pub total: usize,
+    pub free: usize,
 }
 
+/// Hands out leases until the requested number of frames is reserved.
+pub struct PageReservingIter {
+    kfa: &'static KernelFrameAllocator,
+
+    left: usize,
+}
+
+/// Physically contiguous frames that go back to the allocator once dropped.
 #[must_use]
-struct PageRangeLease {
-    start: VirtAddr,
+pub struct PageRangeLease {
+    start: PhysAddr,
     count: usize,
 
     kfa: &'static KernelFrameAllocator,
 }
 
 impl KernelFrameAllocator {
+    /// Name of the backend in use.
+    #[cfg(not(feature = "buddy-allocator"))]
+    pub const BACKEND: &'static str = "free list";
+    #[cfg(feature = "buddy-allocator")]
+    pub const BACKEND: &'static str = "buddy";
+
     /// Calling this will initialize the provided memory regions.
     /// They may only be initialized **once**.
     ///
@@ -54,94 +96,51 @@ impl KernelFrameAllocator {
     /// - The provided memory regions must be unused and correct
     /// - Run before enabling hardware interrupts
     pub unsafe fn init(phys_offset: VirtAddr, map: &'static MemoryRegions) -> &'static Self {
+        let empty = || ZoneFrames {
+            free: Backend::new(OffsetWindow(phys_offset)),
+            total: 0,
+        };
         let mut this = Self {
-            phys_offset,
-            inner: Spinlock::new(InnerAllocator { start: None }),
+            inner: Spinlock::new(InnerAllocator {
+                zones: [empty(), empty(), empty()],
+                leased: 0,
+            }),
         };
 
-        let usable = map
-            .iter()
-            .filter(|mr| mr.kind == MemoryRegionKind::Usable)
-            .cloned();
-
-        let combiner = RegionCombiningIter {
-            inner: usable,
-            current: None,
+        let usable = || {
+            let usable = map
+                .iter()
+                .filter(|mr| mr.kind == MemoryRegionKind::Usable)
+                .map(|mr| mr.start..mr.end);
+            CombinedRegions::new(usable)
         };
 
-        let mut start: Option<NonNull<AlignedNodePage>> = None;
-        let mut last: Option<NonNull<AlignedNodePage>> = None;
-
-        for mr in combiner {
-            // SAFETY: the provided memory region are assumed to be unused and correct
-            let mut node = unsafe { this.init_region(&mr) }
-                .expect("Invalid memory region passed to initializer");
-
-            if start.is_none() {
-                start = Some(node);
-            }
+        #[cfg(feature = "buddy-allocator")]
+        // SAFETY: the usable regions are unused, nothing has been allocated yet
+        let taken = unsafe {
+            let end = map.iter().map(|mr| mr.end).max().unwrap_or(0);
+            this.inner
+                .get_mut()
+                .init_buddy_map(phys_offset, end, usable())
+        };
+        #[cfg(not(feature = "buddy-allocator"))]
+        let taken = 0..0;
 
-            if let Some(last_n) = &mut last {
-                // SAFETY: We performed a null check, therefor the pointer is be valid
-                //         and has been written too before by `init_region`.
-                unsafe {
-                    last_n.as_mut().0.next = Some(node);
-                    node.as_mut().0.prev = last;
-                }
+        for mut region in usable() {
+            if region.contains(&taken.start) {
+                region.start = taken.end;
             }
-
-            last = Some(node);
-        }
-
-        // Finalize the current memory region
-        this.inner.lock().start = Some(start.expect("no suitable memory regions found"));
-
-        unsafe { this.write_self().as_ref().unwrap() }
-    }
-
-    /// Trims and initializes a memory region and returns a pointer to the respective node.
-    ///
-    /// **The pointer may be null on failure!**
-    ///
-    /// # Safety:
-    /// The given memory region must be fully available for usage
-    unsafe fn init_region(&mut self, reg: &MemoryRegion) -> Option<NonNull<AlignedNodePage>> {
-        assert_eq!(reg.kind, MemoryRegionKind::Usable);
-
-        const PAGE_MASK: u64 = !(4096 - 1);
-
-        let aligned_start = reg.start & PAGE_MASK;
-        if aligned_start != reg.start {
-            trace!("Miss aligned memory region start: 0x{:X}", reg.start);
-        }
-
-        let aligned_end = reg.end & PAGE_MASK;
-        if aligned_end != reg.end {
-            trace!("Miss aligned memory region end: 0x{:X}", reg.start);
-        }
-
-        let pages = (aligned_end - aligned_start) / 4096;
-        if pages == 0 {
-            warn!("Useless memory region ignored");
-            return None;
+            // SAFETY: the provided memory region are assumed to be unused and correct
+            unsafe { this.inner.get_mut().add_region(region) };
         }
 
-        let mut node_ptr =
-            NonNull::new((self.phys_offset + aligned_start).as_mut_ptr::<AlignedNodePage>())
-                .unwrap();
-        let node = PageNode {
-            this: node_ptr,
-            next: None,
-            prev: None,
-            count: pages as usize,
-        };
-
-        // SAFETY: we assume that the given memory region is empty and available
-        unsafe {
-            node_ptr.as_ptr().write_volatile(AlignedNodePage(node));
+        debug!("Frame allocator backend: {}", Self::BACKEND);
+        assert_ne!(this.stats().free, 0, "no suitable memory regions found");
+        for stats in this.zone_stats() {
+            debug!("Zone {:?}: {} frames", stats.zone, stats.total);
         }
 
-        Some(node_ptr)
+        unsafe { this.write_self().as_ref().unwrap() }
     }
 
     unsafe fn write_self(mut self) -> *const Self {
@@ -159,55 +158,91 @@ impl KernelFrameAllocator {
 
     // Should only used for bootstrapping
     unsafe fn dirty_alloc_linear_no_map(&mut self, cnt: usize) -> Option<(VirtAddr, usize)> {
-        let mut inner = self.inner.lock();
-
-        let mut node = unsafe { NodeTraverser::new(inner.start?.as_ref().0) }
-            .filter(|n| n.count >= cnt)
-            .next()?;
-
-        // node is the size we need, we dont make a new one
-        if node.count == cnt {
-            if let Some(prev) = &mut node.prev {
-                unsafe {
-                    prev.as_mut().0.next = node.next;
-                }
-            } else {
-                inner.start = node.next;
-            }
-            node.next
-                .as_mut()
-                .map(|next| unsafe { next.as_mut().0.prev = node.prev });
+        let inner = self.inner.get_mut();
+        let start = unsafe { inner.alloc(cnt, PAGE_SIZE, Zone::Normal) }?;
 
-            return Some((VirtAddr::new(node.this.as_ptr() as u64), cnt));
-        }
+        Some((inner.zones[0].free.window().0 + start, cnt))
+    }
 
-        let left = node.count - cnt;
-        let new = unsafe { NonNull::new(node.this.as_ptr().add(left)).unwrap() };
-        // ik we are using this again, but im not sure if llvm can change the location of where we are writing to
-        unsafe {
-            new.as_ptr().write_volatile(AlignedNodePage(PageNode {
-                this: new,
-                next: node.next,
-                prev: node.prev,
-                count: left,
-            }));
+    /// Hands a memory region that was in use until now to the allocator.
+    ///
+    /// Returns how many frames it added.
+    ///
+    /// # Safety
+    /// The region must be unused from now on and may not overlap memory the allocator already manages.
+    pub unsafe fn add_region(&self, region: Range<u64>) -> usize {
+        // SAFETY: guaranteed by the caller
+        unsafe { self.inner.lock().add_region(region) }
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    pub fn allocate_contiguous(&self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_aligned(count, PAGE_SIZE as usize)
+    }
+
+    /// Allocates `count` physically contiguous frames starting at a multiple of `align` bytes.
+    ///
+    /// `align` has to be a power of two of at least 4KiB.
+    pub fn allocate_aligned(&self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        self.allocate_in(count, align, Zone::Normal)
+    }
+
+    /// Like `allocate_aligned`, but only from `zone` and the zones below it.
+    ///
+    /// The lowest zones are only used once everything above is exhausted.
+    pub fn allocate_in(&self, count: usize, align: usize, zone: Zone) -> Option<PhysFrameRange> {
+        // SAFETY: the lists are only modified while holding the lock
+        let start = unsafe { self.inner.lock().alloc(count, align as u64, zone) }?;
+
+        let start = PhysFrame::containing_address(PhysAddr::new(start));
+        Some(PhysFrame::range(start, start + count as u64))
+    }
+
+    /// Returns frames to the allocator.
+    ///
+    /// # Safety
+    /// The frames must have been allocated by this allocator and may not be used anymore.
+    pub unsafe fn deallocate_range(&self, range: PhysFrameRange) {
+        let count = (range.end - range.start) as usize;
+        if count == 0 {
+            return;
         }
 
-        if let Some(prev) = &mut node.prev {
-            unsafe {
-                prev.as_mut().0.next = Some(new);
-            }
-        } else {
-            inner.start = node.next;
+        let start = range.start.start_address().as_u64();
+        // SAFETY: guaranteed by the caller
+        unsafe { self.inner.lock().free(start, count) };
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let inner = self.inner.lock();
+        let total = inner.zones.iter().map(|zone| zone.total).sum();
+        let free = inner.zones.iter().map(|zone| zone.free.free_pages()).sum();
+
+        FrameStats {
+            total,
+            free,
+            used: total - free,
+            leased: inner.leased,
         }
-        node.next
-            .as_mut()
-            .map(|next| unsafe { next.as_mut().0.prev = Some(new) });
+    }
+
+    pub fn zone_stats(&self) -> [ZoneStats; Zone::ALL.len()] {
+        let inner = self.inner.lock();
 
-        Some((VirtAddr::new(node.this.as_ptr() as u64), cnt))
+        Zone::ALL.map(|zone| {
+            let frames = &inner.zones[zone.index()];
+            ZoneStats {
+                zone,
+                total: frames.total,
+                free: frames.free.free_pages(),
+            }
+        })
     }
 
-    unsafe fn reserve_pages(&'static self, cnt: usize) -> PageReservingIter {
+    /// Reserves `cnt` frames as a few leases of physically contiguous frames, largest first.
+    ///
+    /// Stops early once memory runs out.
+    pub fn reserve_pages(&'static self, cnt: usize) -> PageReservingIter {
         PageReservingIter {
             kfa: self,
             left: cnt,
@@ -215,108 +250,142 @@ impl KernelFrameAllocator {
     }
 }
 
-impl<I> Iterator for RegionCombiningIter<I>
-where
-    I: Iterator<Item = MemoryRegion>,
-{
-    type Item = MemoryRegion;
+impl InnerAllocator {
+    /// Trims a memory region to whole pages and adds it to the free lists of its zones.
+    ///
+    /// # Safety:
+    /// The given memory region must be fully available for usage
+    unsafe fn add_region(&mut self, region: Range<u64>) -> usize {
+        let Some(pages) = whole_pages(region.clone()) else {
+            warn!("Useless memory region ignored");
+            return 0;
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        const PAGE_MASK: u64 = !(4096 - 1);
+        if pages.start != region.start {
+            trace!("Miss aligned memory region start: 0x{:X}", region.start);
+        }
+        if pages.end != region.end {
+            trace!("Miss aligned memory region end: 0x{:X}", region.end);
+        }
+
+        let count = ((pages.end - pages.start) / PAGE_SIZE) as usize;
 
-        if self.current.is_none() {
-            self.current = Some(self.inner.next()?);
+        for (zone, part) in zone::split(pages.clone()) {
+            self.zones[zone.index()].total += ((part.end - part.start) / PAGE_SIZE) as usize;
         }
+        // SAFETY: we assume that the given memory region is empty and available
+        unsafe { self.free(pages.start, count) };
+
+        count
+    }
 
-        loop {
-            let Some(next) = self.inner.next() else {
-                return self.current.take();
+    /// Carves the map of the buddy allocators out of the first usable region large
+    /// enough for it and hands it to every zone. Returns the physical range it took.
+    ///
+    /// # Safety
+    /// The usable regions have to be unused and `phys_offset` has to map them.
+    #[cfg(feature = "buddy-allocator")]
+    unsafe fn init_buddy_map(
+        &mut self,
+        phys_offset: VirtAddr,
+        end: u64,
+        mut usable: impl Iterator<Item = Range<u64>>,
+    ) -> Range<u64> {
+        let size = (Backend::map_size(end) as u64).next_multiple_of(PAGE_SIZE);
+        let start = usable
+            .find_map(|region| whole_pages(region).filter(|pages| pages.end - pages.start >= size))
+            .expect("no memory region large enough for the buddy allocator's map")
+            .start;
+
+        let map = NonNull::new((phys_offset + start).as_mut_ptr::<u8>()).unwrap();
+        // SAFETY: the region is unused and mapped by the window, guaranteed by the caller
+        unsafe { map.as_ptr().write_bytes(0, size as usize) };
+
+        for zone in Zone::ALL {
+            let range = zone.range();
+            // SAFETY: the map covers all of physical memory and was just zeroed
+            unsafe {
+                self.zones[zone.index()]
+                    .free
+                    .set_map(map, range.start.min(end)..range.end.min(end))
             };
+        }
 
-            let mut current = self.current.take().unwrap();
-            if current.end != next.start {
-                if (current.end & PAGE_MASK) - (current.start & PAGE_MASK) == 0 {
-                    warn!("Useless memory region ignored");
-                    return self.next();
-                }
+        debug!("Buddy allocator map at 0x{start:X}, {} KiB", size / 1024);
+        start..start + size
+    }
 
-                return Some(current);
-            }
+    /// # Safety
+    /// The lists may only be modified while holding the lock.
+    unsafe fn alloc(&mut self, count: usize, align: u64, zone: Zone) -> Option<u64> {
+        zone.fallbacks()
+            // SAFETY: guaranteed by the caller
+            .find_map(|zone| unsafe { self.zones[zone.index()].free.alloc(count, align) })
+    }
+
+    /// Takes the largest range of at most `max` frames, from the highest zone that has any.
+    ///
+    /// # Safety
+    /// The lists may only be modified while holding the lock.
+    unsafe fn alloc_largest(&mut self, max: usize) -> Option<FreeRange> {
+        Zone::Normal
+            .fallbacks()
+            // SAFETY: guaranteed by the caller
+            .find_map(|zone| unsafe { self.zones[zone.index()].free.alloc_largest(max) })
+    }
 
-            current.end = next.end;
-            self.current = Some(current);
+    /// Frees frames into the zones they belong to.
+    ///
+    /// # Safety
+    /// The frames must be unused and not be free already.
+    unsafe fn free(&mut self, start: u64, count: usize) {
+        let end = start + count as u64 * PAGE_SIZE;
+
+        for (zone, part) in zone::split(start..end) {
+            let count = ((part.end - part.start) / PAGE_SIZE) as usize;
+            // SAFETY: guaranteed by the caller
+            unsafe { self.zones[zone.index()].free.free(part.start, count) };
         }
     }
 }
 
+// SAFETY: the bootloader maps all of physical memory at the offset
+unsafe impl PhysWindow for OffsetWindow {
+    fn page(&self, addr: u64) -> NonNull<u8> {
+        NonNull::new((self.0 + addr).as_mut_ptr()).unwrap()
+    }
+}
+
 impl Iterator for PageReservingIter {
     type Item = PageRangeLease;
 
+    /// Hands out the largest contiguous range available, until `left` pages are reserved.
     fn next(&mut self) -> Option<Self::Item> {
         if self.left == 0 {
             return None;
         }
 
         let mut inner = self.kfa.inner.lock();
-        // SAFETY: when the page is present it's readable
-        let mut node = unsafe { inner.start?.as_ref() }.0;
-
-        // if the node is >= of the node size we remove the node and take it
-        Some(if self.left >= node.count {
-            self.left -= node.count;
-
-            if node.prev.is_none() {
-                inner.start = node.next;
-            }
+        // SAFETY: the lists are only modified while holding the lock
+        let range = unsafe { inner.alloc_largest(self.left) }?;
 
-            // SAFETY: we assume the ll is valid
-            let _ = node.prev.as_mut().map(|prev| unsafe {
-                prev.as_mut().0.next = node.next;
-            });
-            let _ = node.next.as_mut().map(|next| unsafe {
-                next.as_mut().0.prev = node.prev;
-            });
-
-            PageRangeLease {
-                start: VirtAddr::new(node.this.as_ptr() as u64),
-                count: node.count,
-                kfa: self.kfa,
-            }
-        } else {
-            node.count -= self.left;
-            self.left = 0;
-
-            let origin = VirtAddr::new(node.this.as_ptr() as u64);
-            // SAFETY: We know we can bump up the pointer as its within the nodes range
-            node.this = NonNull::new(unsafe { node.this.as_ptr().add(self.left) }).unwrap();
-
-            if node.prev.is_none() {
-                inner.start = NonNull::new(origin.as_mut_ptr());
-            }
-
-            // SAFETY: we assume the ll is valid
-            let _ = node.prev.as_mut().map(|prev| unsafe {
-                prev.as_mut().0.next = Some(node.this);
-            });
-            let _ = node.next.as_mut().map(|next| unsafe {
-                next.as_mut().0.prev = Some(node.this);
-            });
-
-            // SAFETY:
-            unsafe {
-                node.this.as_ptr().write_volatile(AlignedNodePage(node));
-            }
+        inner.leased += range.count;
+        self.left -= range.count;
 
-            PageRangeLease {
-                start: origin,
-                count: self.left,
-                kfa: self.kfa,
-            }
+        Some(PageRangeLease {
+            start: PhysAddr::new(range.start),
+            count: range.count,
+            kfa: self.kfa,
         })
     }
 }
 
 impl PageRangeLease {
+    pub fn frames(&self) -> PhysFrameRange {
+        let start = PhysFrame::containing_address(self.start);
+        PhysFrame::range(start, start + self.count as u64)
+    }
+
     pub fn release(self) {
         let _ = self;
     }
@@ -329,32 +398,38 @@ impl PageRangeLease {
 impl Drop for PageRangeLease {
     fn drop(&mut self) {
         let mut inner = self.kfa.inner.lock();
+        inner.leased -= self.count;
 
-        let page = PageNode {
-            this: NonNull::new(self.start.as_mut_ptr()).unwrap(),
-            next: inner.start,
-            prev: None, // this is none on purpose as this will become the first page
-            count: self.count,
-        };
+        // SAFETY: the lease owned these pages, they are not used anymore
+        unsafe { inner.free(self.start.as_u64(), self.count) };
+    }
+}
 
-        if let Some(mut old) = inner.start {
-            unsafe { old.as_mut().0.prev = Some(page.this) };
-        }
+unsafe impl<S: PageSize> FrameAllocator<S> for &KernelFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let range = self.allocate_aligned((S::SIZE / 4096) as usize, S::SIZE as usize)?;
 
-        unsafe {
-            page.this.as_ptr().write_volatile(AlignedNodePage(page));
-        }
-        inner.start = Some(page.this);
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for &KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+
+        // SAFETY: guaranteed by the caller
+        unsafe { self.deallocate_range(PhysFrame::range(start, start + S::SIZE / 4096)) };
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        // SAFETY: We pray
-        let (frame, _) = unsafe { self.dirty_alloc_linear_no_map(1) }?;
+        FrameAllocator::<Size4KiB>::allocate_frame(&mut &*self)
+    }
+}
 
-        Some(PhysFrame::containing_address(PhysAddr::new(
-            frame.as_u64() - self.phys_offset.as_u64(),
-        )))
+impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(&mut &*self, frame) }
     }
 }
@@ -0,0 +1,57 @@
+use core::ops::Range;
+
+const DMA16_END: u64 = 16 * 1024 * 1024;
+const DMA32_END: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Part of physical memory that devices with limited addressing can reach.
+///
+/// Allocations for a zone may be served from every zone below it as well.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16MiB, for ISA DMA.
+    Dma16,
+    /// Below 4GiB, for devices with 32 bit addressing.
+    Dma32,
+    /// Everything else.
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma16, Zone::Dma32, Zone::Normal];
+
+    /// Physical addresses belonging to this zone.
+    pub const fn range(self) -> Range<u64> {
+        match self {
+            Zone::Dma16 => 0..DMA16_END,
+            Zone::Dma32 => DMA16_END..DMA32_END,
+            Zone::Normal => DMA32_END..u64::MAX,
+        }
+    }
+
+    pub fn of(addr: u64) -> Zone {
+        match addr {
+            0..DMA16_END => Zone::Dma16,
+            DMA16_END..DMA32_END => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+
+    pub(super) fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Zones an allocation for this zone may come from, preferring the highest one.
+    pub(super) fn fallbacks(self) -> impl Iterator<Item = Zone> {
+        Zone::ALL.into_iter().take(self.index() + 1).rev()
+    }
+}
+
+/// The parts of `range` in every zone it touches.
+pub(super) fn split(range: Range<u64>) -> impl Iterator<Item = (Zone, Range<u64>)> {
+    Zone::ALL.into_iter().filter_map(move |zone| {
+        let bounds = zone.range();
+        let part = range.start.max(bounds.start)..range.end.min(bounds.end);
+
+        (part.start < part.end).then_some((zone, part))
+    })
+}
@@ -0,0 +1,360 @@
+use crate::mem::{InnerMemoryManager, MemoryManager};
+use core::fmt::{Display, Formatter};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_PAT: u32 = 0x277;
+
+/// PAT entries selected through PWT and PCD, the PAT bit itself is left clear.
+///
+/// Entry 0 stays write back, so every mapping the bootloader made keeps its meaning.
+const PAT_LAYOUT: [CacheMode; 4] = [
+    CacheMode::WriteBack,
+    CacheMode::WriteCombining,
+    CacheMode::WriteThrough,
+    CacheMode::Uncacheable,
+];
+
+/// Above this many pages the whole TLB is flushed instead of single entries.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteCombining,
+    WriteThrough,
+    Uncacheable,
+}
+
+/// Access rights and caching of a mapping.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MapFlags {
+    pub writable: bool,
+    pub no_execute: bool,
+    pub user: bool,
+    pub global: bool,
+    pub cache: CacheMode,
+}
+
+/// What an address is mapped to.
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+    /// Physical address the queried address translates to.
+    pub phys: PhysAddr,
+    /// Size of the page containing the address.
+    pub page_size: u64,
+    pub flags: MapFlags,
+}
+
+impl CacheMode {
+    /// Memory type encoding used in the PAT MSR.
+    fn pat_type(self) -> u64 {
+        match self {
+            CacheMode::Uncacheable => 0x00,
+            CacheMode::WriteCombining => 0x01,
+            CacheMode::WriteThrough => 0x04,
+            CacheMode::WriteBack => 0x06,
+        }
+    }
+}
+
+impl Display for CacheMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            CacheMode::WriteBack => "WB",
+            CacheMode::WriteCombining => "WC",
+            CacheMode::WriteThrough => "WT",
+            CacheMode::Uncacheable => "UC",
+        })
+    }
+}
+
+impl Display for MapFlags {
+    /// `rwx u g WB` style, with `-` for missing rights and `k` for kernel only.
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "r{}{} {} {} {}",
+            if self.writable { 'w' } else { '-' },
+            if self.no_execute { '-' } else { 'x' },
+            if self.user { 'u' } else { 'k' },
+            if self.global { 'g' } else { '-' },
+            self.cache
+        )
+    }
+}
+
+impl MapFlags {
+    /// Kernel data: writable, not executable.
+    pub const DATA: Self = Self {
+        writable: true,
+        no_execute: true,
+        user: false,
+        global: false,
+        cache: CacheMode::WriteBack,
+    };
+
+    /// Kernel read only data.
+    pub const RODATA: Self = Self {
+        writable: false,
+        ..Self::DATA
+    };
+
+    /// Device registers.
+    pub const MMIO: Self = Self {
+        cache: CacheMode::Uncacheable,
+        ..Self::DATA
+    };
+
+    pub const fn with_cache(self, cache: CacheMode) -> Self {
+        Self { cache, ..self }
+    }
+
+    pub(super) fn to_pte(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        flags.set(PageTableFlags::WRITABLE, self.writable);
+        flags.set(PageTableFlags::NO_EXECUTE, self.no_execute);
+        flags.set(PageTableFlags::USER_ACCESSIBLE, self.user);
+        flags.set(PageTableFlags::GLOBAL, self.global);
+
+        let index = PAT_LAYOUT
+            .iter()
+            .position(|&mode| mode == self.cache)
+            .unwrap();
+        flags.set(PageTableFlags::WRITE_THROUGH, index & 1 != 0);
+        flags.set(PageTableFlags::NO_CACHE, index & 2 != 0);
+
+        flags
+    }
+
+    pub(super) fn from_pte(flags: PageTableFlags) -> Self {
+        let index = flags.contains(PageTableFlags::WRITE_THROUGH) as usize
+            | (flags.contains(PageTableFlags::NO_CACHE) as usize) << 1;
+
+        Self {
+            writable: flags.contains(PageTableFlags::WRITABLE),
+            no_execute: flags.contains(PageTableFlags::NO_EXECUTE),
+            user: flags.contains(PageTableFlags::USER_ACCESSIBLE),
+            global: flags.contains(PageTableFlags::GLOBAL),
+            cache: PAT_LAYOUT[index],
+        }
+    }
+}
+
+/// Programs the PAT so every `CacheMode` can be selected with PWT and PCD.
+///
+/// # Safety
+/// Has to run before anything maps memory with a cache mode other than write back.
+pub(super) unsafe fn init_pat() {
+    let pat = PAT_LAYOUT
+        .iter()
+        .chain(PAT_LAYOUT.iter())
+        .enumerate()
+        .fold(0, |pat, (i, mode)| pat | mode.pat_type() << (i * 8));
+
+    // SAFETY: entry 0 is unchanged and no other entry is in use yet
+    unsafe { Msr::new(IA32_PAT).write(pat) };
+    tlb::flush_all();
+}
+
+impl MemoryManager {
+    /// Maps every page of `pages` to a freshly allocated frame.
+    ///
+    /// On failure nothing of `pages` stays mapped.
+    pub fn map_anonymous(
+        &self,
+        pages: PageRange,
+        flags: MapFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let mut inner = self.inner.lock();
+
+        for (i, page) in pages.enumerate() {
+            if let Err(err) = inner.map_fresh(page, flags.to_pte()) {
+                // SAFETY: these pages were only just mapped, nobody uses them yet
+                let _ = unsafe {
+                    inner.unmap_range(Page::range(pages.start, pages.start + i as u64), true)
+                };
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps `pages` to `frames`, e.g. to reach device memory.
+    ///
+    /// On failure nothing of `pages` stays mapped.
+    ///
+    /// # Safety
+    /// The frames must not be used for anything that conflicts with the new mapping.
+    pub unsafe fn map_phys(
+        &self,
+        pages: PageRange,
+        frames: PhysFrameRange,
+        flags: MapFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert_eq!(pages.count(), frames.count(), "range sizes differ");
+
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+
+        for (i, (page, frame)) in pages.zip(frames).enumerate() {
+            // SAFETY: guaranteed by the caller
+            let result = unsafe {
+                inner
+                    .mapper
+                    .map_to(page, frame, flags.to_pte(), &mut inner.allocator)
+            };
+
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // SAFETY: these pages were only just mapped, nobody uses them yet
+                    let _ = unsafe {
+                        inner.unmap_range(Page::range(pages.start, pages.start + i as u64), false)
+                    };
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps `pages` and frees the frames behind them, pages that are not mapped are skipped.
+    ///
+    /// # Safety
+    /// Nothing may use the pages anymore and the frames must have been mapped by
+    /// `map_anonymous`.
+    pub unsafe fn unmap_anonymous(&self, pages: PageRange) -> Result<(), UnmapError> {
+        // SAFETY: guaranteed by the caller
+        unsafe { self.inner.lock().unmap_range(pages, true) }
+    }
+
+    /// Unmaps `pages` without touching the frames, pages that are not mapped are skipped.
+    ///
+    /// # Safety
+    /// Nothing may use the pages anymore.
+    pub unsafe fn unmap(&self, pages: PageRange) -> Result<(), UnmapError> {
+        // SAFETY: guaranteed by the caller
+        unsafe { self.inner.lock().unmap_range(pages, false) }
+    }
+
+    /// Changes the flags of every page in `pages`.
+    ///
+    /// # Safety
+    /// Nothing may rely on the old permissions anymore, e.g. write to a page that becomes read only.
+    pub unsafe fn protect(&self, pages: PageRange, flags: MapFlags) -> Result<(), FlagUpdateError> {
+        let mut inner = self.inner.lock();
+
+        let mut result = Ok(());
+        for page in pages {
+            // SAFETY: guaranteed by the caller
+            result = unsafe { inner.mapper.update_flags(page, flags.to_pte()) }.map(|f| f.ignore());
+            if result.is_err() {
+                break;
+            }
+        }
+
+        shootdown(pages);
+        result
+    }
+
+    /// Looks up what `addr` is mapped to.
+    pub fn query(&self, addr: VirtAddr) -> Option<Mapping> {
+        match self.inner.lock().mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some(Mapping {
+                phys: frame.start_address() + offset,
+                page_size: frame.size(),
+                flags: MapFlags::from_pte(flags),
+            }),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+}
+
+impl InnerMemoryManager {
+    fn map_fresh(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let frame: PhysFrame = self
+            .allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // SAFETY: the frame was just allocated, nothing else refers to it
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                // SAFETY: the frame never got mapped
+                unsafe { self.allocator.deallocate_frame(frame) };
+                Err(err)
+            }
+        }
+    }
+
+    /// # Safety
+    /// Nothing may use the pages anymore, with `free` their frames have to be owned by them.
+    unsafe fn unmap_range(&mut self, pages: PageRange, free: bool) -> Result<(), UnmapError> {
+        let mut result = Ok(());
+
+        for page in pages {
+            match self.mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.ignore();
+                    if free {
+                        // SAFETY: guaranteed by the caller
+                        unsafe { self.allocator.deallocate_frame(frame) };
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => (),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        shootdown(pages);
+        result
+    }
+}
+
+/// Drops stale translations of `pages` from the TLB.
+///
+/// Only the boot CPU is running, so flushing it locally covers every TLB in the system.
+fn shootdown(pages: PageRange) {
+    if pages.count() > FLUSH_ALL_THRESHOLD {
+        // a CR3 reload keeps global entries, toggling PGE flushes everything
+        let cr4 = Cr4::read();
+        if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            // SAFETY: only PGE changes and it is restored right away
+            unsafe {
+                Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+                Cr4::write(cr4);
+            }
+        } else {
+            tlb::flush_all();
+        }
+        return;
+    }
+
+    for page in pages {
+        tlb::flush(page.start_address());
+    }
+}
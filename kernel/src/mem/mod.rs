@@ -1,20 +1,60 @@
-use crate::mem::kfalloc::KernelFrameAllocator;
+pub use crate::mem::kfalloc::{FrameStats, Zone};
+
+use crate::mem::kfalloc::{KernelFrameAllocator, PageReservingIter};
+use crate::mem::tables::KernelLayout;
+use crate::mem::vspace::VirtualSpace;
 use bootloader_api::info::MemoryRegions;
+use conquer_once::spin::OnceCell;
+use core::ops::Range;
 use spinning_top::Spinlock;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::UnmapError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+};
 use x86_64::{PhysAddr, VirtAddr};
 
+#[cfg(feature = "frame-bench")]
+mod bench;
+mod dma;
+#[cfg(feature = "heap-debug")]
+mod heap_debug;
+mod image;
+mod inspect;
 pub mod kalloc;
 mod kfalloc;
+mod map;
+mod reclaim;
+mod report;
+mod selftest;
+mod slab;
+mod tables;
+mod vspace;
+
+#[cfg(feature = "heap-debug")]
+pub use heap_debug::DebugAllocator;
+pub use image::KernelImage;
+pub use inspect::{for_each_mapping, for_each_page_table, PageTableDump};
+pub use map::{CacheMode, MapFlags};
+pub use report::RegionClass;
+pub use slab::{for_each_cache, SlabCache};
+pub use vspace::RegionError;
+
+static PHYS_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+static FRAME_ALLOCATOR: OnceCell<&'static KernelFrameAllocator> = OnceCell::uninit();
 
 pub struct MemoryManager {
     inner: Spinlock<InnerMemoryManager>,
+    vspace: Spinlock<VirtualSpace>,
+    regions: &'static MemoryRegions,
     phys_offset: VirtAddr,
+    kernel: KernelImage,
 }
 
+// SAFETY: all mutable state lives behind the spinlock, the memory map is only ever read
+unsafe impl Sync for MemoryManager {}
+
 struct InnerMemoryManager {
-    regions: &'static MemoryRegions,
     mapper: OffsetPageTable<'static>,
     allocator: &'static KernelFrameAllocator,
 }
@@ -23,63 +63,116 @@ impl MemoryManager {
     pub unsafe fn new(
         phys_offset: VirtAddr,
         regions: &'static MemoryRegions,
+        kernel: KernelImage,
+        framebuffer: Option<Range<VirtAddr>>,
     ) -> &'static MemoryManager {
+        PHYS_OFFSET.init_once(|| phys_offset);
+
         let cr3 = Cr3::read().0.start_address().as_u64();
         // SAFETY: the caller of current function has to guarantee phys_offset is correct
         let level4 = unsafe { &mut *((phys_offset.as_u64() + cr3) as *mut PageTable) };
 
         // SAFETY: the caller of current function has to guarantee phys_offset is correct
-        let mapper = unsafe { OffsetPageTable::new(level4, phys_offset) };
+        let mut mapper = unsafe { OffsetPageTable::new(level4, phys_offset) };
 
-        // SAFETY: First time we are touching these regions, therefore we can initialize them
-        let mut allocator = unsafe { KernelFrameAllocator::init(phys_offset, regions) };
+        let mut vspace = VirtualSpace::new(mapper.level_4_table());
+        let phys_end = regions.iter().map(|mr| mr.end).max().unwrap_or(0);
+        // the window gets mapped with huge pages of up to 1GiB
+        vspace.reserve(phys_offset, phys_end.next_multiple_of(1 << 30));
+        let image = kernel.span();
+        vspace.reserve(image.start, image.end - image.start);
 
-        let inner = InnerMemoryManager {
-            regions,
-            mapper,
-            allocator,
+        // SAFETY: nothing has been mapped with other cache modes yet
+        unsafe { map::init_pat() };
+
+        // SAFETY: First time we are touching these regions, therefore we can initialize them
+        let allocator = unsafe { KernelFrameAllocator::init(phys_offset, regions) };
+        FRAME_ALLOCATOR.init_once(|| allocator);
+
+        let layout = KernelLayout {
+            phys_offset,
+            phys_end,
+            kernel: &kernel,
+            framebuffer,
         };
+        // SAFETY: only the bootloader has mapped anything so far and the PAT is set up
+        let mapper = unsafe { tables::switch_to_kernel_tables(&mapper, layout, allocator) };
+
+        let inner = InnerMemoryManager { mapper, allocator };
 
-        let mmf = (phys_offset.as_u64()
-            + allocator
-                .allocate_frame()
-                .expect("we should really have a second one")
-                .start_address()
-                .as_u64()) as *mut MemoryManager;
+        // the manager lives as long as the kernel, so its frame is never given back
+        let lease = allocator
+            .reserve_pages(1)
+            .next()
+            .expect("we should really have a second one");
+        let frame = lease.frames().start;
+        lease.keep();
+        let mmf = (phys_offset.as_u64() + frame.start_address().as_u64()) as *mut MemoryManager;
 
         unsafe {
             mmf.write_volatile(MemoryManager {
                 inner: Spinlock::new(inner),
+                vspace: Spinlock::new(vspace),
+                regions,
                 phys_offset,
+                kernel,
             })
         };
 
         unsafe { mmf.as_ref() }.unwrap()
     }
 
+    pub fn frame_stats(&self) -> FrameStats {
+        self.inner.lock().allocator.stats()
+    }
+
+    /// Leases `count` frames in a few physically contiguous ranges, see `PageRangeLease`.
+    pub fn reserve_frames(&self, count: usize) -> PageReservingIter {
+        self.inner.lock().allocator.reserve_pages(count)
+    }
+
     pub fn translate<T>(&self, addr: PhysAddr) -> *const T {
         translate_(self.phys_offset, addr)
     }
 
-    pub fn translate_mut<T>(&self, addr: PhysAddr) -> *mut T {
-        translate_mut_(self.phys_offset, addr)
+    /// Unmaps `page` so any access to it faults.
+    ///
+    /// The backing frame is not returned to the allocator.
+    ///
+    /// # Safety
+    /// Nothing may still be using the page.
+    pub unsafe fn set_guard_page(&self, page: Page<Size4KiB>) -> Result<(), UnmapError> {
+        let (_frame, flush) = self.inner.lock().mapper.unmap(page)?;
+        flush.flush();
+
+        Ok(())
     }
 }
 
-impl InnerMemoryManager {
-    pub fn alloc_self(&mut self) {}
-}
-
 unsafe impl FrameAllocator<Size4KiB> for InnerMemoryManager {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        todo!()
+        self.allocator.allocate_frame()
     }
 }
 
-pub fn translate_<T>(offset: VirtAddr, addr: PhysAddr) -> *const T {
-    (offset.as_u64() + addr.as_u64()) as *const T
+impl FrameDeallocator<Size4KiB> for InnerMemoryManager {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { self.allocator.deallocate_frame(frame) }
+    }
 }
 
-pub fn translate_mut_<T>(offset: VirtAddr, addr: PhysAddr) -> *mut T {
-    (offset.as_u64() + addr.as_u64()) as *mut T
+/// Offset at which the bootloader mapped all of physical memory.
+///
+/// Returns `None` until the `MemoryManager` has been created.
+pub fn phys_offset() -> Option<VirtAddr> {
+    PHYS_OFFSET.try_get().ok().copied()
+}
+
+/// The frame allocator for code that can't go through the `MemoryManager`.
+fn frame_allocator() -> Option<&'static KernelFrameAllocator> {
+    FRAME_ALLOCATOR.try_get().ok().copied()
+}
+
+pub fn translate_<T>(offset: VirtAddr, addr: PhysAddr) -> *const T {
+    (offset.as_u64() + addr.as_u64()) as *const T
 }
@@ -0,0 +1,97 @@
+use crate::mem::{for_each_mapping, for_each_page_table, MemoryManager, RegionClass};
+use alloc::vec::Vec;
+use core::ops::Range;
+use log::info;
+use page_list::CombinedRegions;
+use x86_64::PhysAddr;
+
+impl MemoryManager {
+    /// Hands every region of the memory map belonging to `class` to the frame allocator.
+    ///
+    /// Frames that are still mapped outside the physical memory window, the active
+    /// page tables and everything in `keep` are left alone. Returns how many frames
+    /// were reclaimed.
+    ///
+    /// Needs the heap. Meant for `Bootloader` memory once the kernel runs on its own page
+    /// tables and for `AcpiReclaimable` memory once the ACPI tables have been parsed.
+    ///
+    /// # Safety
+    /// Nothing may access memory of `class` through the physical memory window anymore,
+    /// except for the ranges in `keep`. Every class may only be reclaimed once.
+    pub unsafe fn reclaim(&self, class: RegionClass, keep: &[Range<PhysAddr>]) -> usize {
+        let mut excluded = self.in_use(keep);
+        excluded.sort_unstable_by_key(|range| range.start);
+
+        let regions = self
+            .regions
+            .iter()
+            .filter(|region| RegionClass::of(region.kind) == class)
+            .map(|region| region.start..region.end);
+
+        let allocator = self.inner.lock().allocator;
+        let mut reclaimed = 0;
+
+        for region in CombinedRegions::new(regions) {
+            for free in subtract(region, &excluded) {
+                // SAFETY: guaranteed by the caller, everything still in use got excluded
+                reclaimed += unsafe { allocator.add_region(free) };
+            }
+        }
+
+        info!("Reclaimed {} KiB of {class} memory", reclaimed * 4);
+        reclaimed
+    }
+
+    /// Physical ranges that are still reachable without the physical memory window.
+    fn in_use(&self, keep: &[Range<PhysAddr>]) -> Vec<Range<u64>> {
+        let window = self.phys_offset.as_u64()..self.phys_offset.as_u64() + self.phys_end();
+
+        let mut used: Vec<_> = keep
+            .iter()
+            .map(|range| range.start.as_u64()..range.end.as_u64())
+            .collect();
+
+        for_each_page_table(|table| used.push(table.as_u64()..table.as_u64() + 4096));
+        for_each_mapping(|range| {
+            if !window.contains(&range.start.as_u64()) {
+                used.push(range.phys.as_u64()..range.phys.as_u64() + range.size);
+            }
+        });
+
+        used
+    }
+
+    /// End of the highest region in the memory map.
+    fn phys_end(&self) -> u64 {
+        self.regions.iter().map(|mr| mr.end).max().unwrap_or(0)
+    }
+}
+
+/// The parts of `region` not covered by `excluded`, which has to be sorted by start.
+fn subtract(region: Range<u64>, excluded: &[Range<u64>]) -> impl Iterator<Item = Range<u64>> + '_ {
+    let Range { start, end } = region;
+    let mut cursor = start;
+    let mut excluded = excluded
+        .iter()
+        .filter(move |ex| ex.start < end && start < ex.end);
+
+    core::iter::from_fn(move || {
+        while cursor < end {
+            match excluded.next() {
+                Some(ex) if ex.start <= cursor => cursor = cursor.max(ex.end),
+                Some(ex) => {
+                    let free = cursor..ex.start;
+                    cursor = ex.end;
+                    return Some(free);
+                }
+                None => {
+                    let free = cursor..end;
+                    cursor = end;
+                    return Some(free);
+                }
+            }
+        }
+
+        None
+    })
+}
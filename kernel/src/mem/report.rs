@@ -0,0 +1,180 @@
+use crate::mem::inspect::{count_page_tables, Size};
+use crate::mem::kalloc::heap_size;
+use crate::mem::{for_each_cache, MemoryManager};
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::fmt::{Display, Formatter};
+
+/// Distinct region classes a memory map is summed up by.
+const MAX_CLASSES: usize = 16;
+
+/// What a region of the bootloader's memory map is used for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionClass {
+    Usable,
+    Bootloader,
+    UefiRuntime,
+    AcpiReclaimable,
+    AcpiNvs,
+    Mmio,
+    Reserved,
+    Unusable,
+    /// UEFI memory type without a class of its own.
+    Uefi(u32),
+    /// E820 type without a class of its own.
+    Bios(u32),
+    Unknown,
+}
+
+/// Every region of the bootloader's memory map, followed by the totals per class.
+pub struct MemoryMapReport(&'static MemoryRegions);
+
+/// Where the frames of the machine went.
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryUsage {
+    /// Frames given to the frame allocator.
+    pub total: usize,
+    pub free: usize,
+    pub leased: usize,
+    /// Page tables reachable from CR3, the kernel built all of them.
+    pub page_tables: usize,
+    pub heap: usize,
+    pub slabs: usize,
+    pub kernel_image: usize,
+}
+
+impl RegionClass {
+    pub fn of(kind: MemoryRegionKind) -> Self {
+        match kind {
+            MemoryRegionKind::Usable => Self::Usable,
+            MemoryRegionKind::Bootloader => Self::Bootloader,
+            MemoryRegionKind::UnknownUefi(ty) => match ty {
+                0 => Self::Reserved,
+                5 | 6 => Self::UefiRuntime,
+                8 => Self::Unusable,
+                9 => Self::AcpiReclaimable,
+                10 => Self::AcpiNvs,
+                11 | 12 => Self::Mmio,
+                _ => Self::Uefi(ty),
+            },
+            MemoryRegionKind::UnknownBios(ty) => match ty {
+                2 => Self::Reserved,
+                3 => Self::AcpiReclaimable,
+                4 => Self::AcpiNvs,
+                5 => Self::Unusable,
+                _ => Self::Bios(ty),
+            },
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl Display for RegionClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Usable => write!(f, "usable"),
+            Self::Bootloader => write!(f, "bootloader"),
+            Self::UefiRuntime => write!(f, "UEFI runtime"),
+            Self::AcpiReclaimable => write!(f, "ACPI reclaimable"),
+            Self::AcpiNvs => write!(f, "ACPI NVS"),
+            Self::Mmio => write!(f, "MMIO"),
+            Self::Reserved => write!(f, "reserved"),
+            Self::Unusable => write!(f, "unusable"),
+            Self::Uefi(ty) => write!(f, "UEFI type {ty}"),
+            Self::Bios(ty) => write!(f, "E820 type {ty}"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Display for MemoryMapReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let mut totals = [(RegionClass::Unknown, 0u64, 0usize); MAX_CLASSES];
+        let mut classes = 0;
+
+        for region in self.0.iter() {
+            let class = RegionClass::of(region.kind);
+            let size = region.end - region.start;
+
+            writeln!(
+                f,
+                "{:#013x}-{:#013x} {} {class}",
+                region.start,
+                region.end,
+                Size(size)
+            )?;
+
+            match totals[..classes].iter_mut().find(|(c, ..)| *c == class) {
+                Some((_, bytes, count)) => {
+                    *bytes += size;
+                    *count += 1;
+                }
+                None if classes < MAX_CLASSES => {
+                    totals[classes] = (class, size, 1);
+                    classes += 1;
+                }
+                None => (),
+            }
+        }
+
+        writeln!(f, "Totals:")?;
+        for (class, bytes, count) in &totals[..classes] {
+            writeln!(f, "{:>8} KiB in {count:>3} regions {class}", bytes / 1024)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for MemoryUsage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let rows = [
+            ("total", self.total),
+            ("free", self.free),
+            ("leased", self.leased),
+            ("page tables", self.page_tables),
+            ("heap", self.heap),
+            ("slabs", self.slabs),
+            ("kernel image", self.kernel_image),
+        ];
+
+        for (name, frames) in rows {
+            writeln!(f, "{name:>12}: {frames:>8} frames, {:>8} KiB", frames * 4)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MemoryManager {
+    /// The memory map as handed over by the bootloader.
+    pub fn memory_map(&self) -> MemoryMapReport {
+        MemoryMapReport(self.regions)
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let frames = self.frame_stats();
+
+        let mut slabs = 0;
+        for_each_cache(|_, stats| slabs += stats.slabs);
+
+        let kernel_image = self
+            .kernel
+            .segments()
+            .map(|seg| {
+                let start = seg.start.align_down(4096u64);
+                let end = (seg.start + seg.size).align_up(4096u64);
+                ((end - start) / 4096) as usize
+            })
+            .sum();
+
+        MemoryUsage {
+            total: frames.total,
+            free: frames.free,
+            leased: frames.leased,
+            page_tables: count_page_tables(),
+            heap: heap_size() / 4096,
+            slabs,
+            kernel_image,
+        }
+    }
+}
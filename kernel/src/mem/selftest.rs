@@ -0,0 +1,152 @@
+use crate::mem::{MapFlags, MemoryManager, SlabCache, Zone};
+use alloc::vec::Vec;
+use log::info;
+use x86_64::VirtAddr;
+
+/// Cache that only the self-test allocates from.
+static TEST_CACHE: SlabCache<[u64; 4]> = SlabCache::new("self-test", || [0; 4]);
+
+impl MemoryManager {
+    /// Runs the allocators nothing at boot relies on yet through a round of
+    /// allocations, panics if one of them misbehaves.
+    ///
+    /// Everything is given back before it returns. Needs the heap.
+    pub fn self_test(&self) {
+        slab_cache();
+        mappings(self);
+        stack(self);
+        dma(self);
+        leases(self);
+        info!("Memory self-test passed");
+    }
+}
+
+/// Allocates until the cache needs a second slab, then frees everything again.
+fn slab_cache() {
+    let mut objects = Vec::new();
+    while TEST_CACHE.stats().slabs < 2 {
+        let object = TEST_CACHE.alloc().expect("no frame left for a slab");
+        objects.push(object);
+    }
+
+    let stats = TEST_CACHE.stats();
+    assert_eq!(stats.in_use, objects.len());
+    assert!(stats.capacity >= objects.len());
+
+    // objects may not overlap, not even across slabs
+    for (i, object) in objects.iter_mut().enumerate() {
+        object.fill(i as u64);
+    }
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(**object, [i as u64; 4], "slab objects overlap");
+    }
+
+    drop(objects);
+    let stats = TEST_CACHE.stats();
+    assert_eq!((stats.slabs, stats.in_use, stats.capacity), (0, 0, 0));
+    assert_eq!(stats.allocs, stats.frees);
+    assert_eq!(stats.slabs_created, stats.slabs_released);
+}
+
+/// Maps a page, takes write access away and unmaps it again.
+fn mappings(mem: &MemoryManager) {
+    let pages = mem.reserve_virtual(1, 4096).expect("no virtual space left");
+    let addr = pages.start.start_address();
+    mem.map_anonymous(pages, MapFlags::DATA)
+        .expect("failed to map a page");
+
+    let mapping = mem.query(addr).expect("fresh page not mapped");
+    assert_eq!((mapping.page_size, mapping.flags), (4096, MapFlags::DATA));
+    // SAFETY: the page is mapped and only used here
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(0x5A5A) };
+    // SAFETY: the frame is mapped through the physical memory window as well
+    assert_eq!(
+        unsafe { mem.translate::<u64>(mapping.phys).read_volatile() },
+        0x5A5A,
+        "query returned the wrong frame"
+    );
+
+    // SAFETY: nothing writes to the page anymore
+    unsafe { mem.protect(pages, MapFlags::RODATA) }.expect("failed to protect a page");
+    assert_eq!(mem.query(addr).map(|m| m.flags), Some(MapFlags::RODATA));
+
+    // SAFETY: the page was mapped by `map_anonymous` and isn't used anymore
+    unsafe {
+        mem.unmap_anonymous(pages).expect("failed to unmap a page");
+        mem.release_virtual(pages);
+    }
+    assert!(mem.query(addr).is_none(), "unmapped page still mapped");
+}
+
+/// Allocates a stack and makes sure only the guard page below it is left unmapped.
+fn stack(mem: &MemoryManager) {
+    let stack = mem.alloc_stack(4).expect("failed to allocate a stack");
+    let top = stack.top() - 8u64;
+
+    let mapping = mem.query(top).expect("stack not mapped");
+    assert!(mapping.flags.writable, "stack not writable");
+    assert!(
+        mem.query(stack.guard().start_address()).is_none(),
+        "guard page mapped"
+    );
+
+    // SAFETY: the stack was never used
+    unsafe { mem.free_stack(stack) };
+    assert!(mem.query(top).is_none(), "freed stack still mapped");
+}
+
+/// Allocates a buffer the way an ISA DMA driver would: below 16MiB, within a 64KiB boundary.
+fn dma(mem: &MemoryManager) {
+    let free = || {
+        let stats = mem.zone_stats();
+        stats
+            .iter()
+            .find(|s| s.zone == Zone::Dma16)
+            .map_or(0, |s| s.free)
+    };
+    let before = free();
+
+    // the lowest zone may be used up entirely by the firmware and the bootloader
+    let Some(buffer) = mem.alloc_dma(16, 64 * 1024, Zone::Dma16) else {
+        return;
+    };
+    let end = buffer.phys() + buffer.size();
+    assert_eq!(
+        Zone::of(end.as_u64() - 1),
+        Zone::Dma16,
+        "DMA buffer too high"
+    );
+    assert!(
+        buffer.phys().is_aligned(64 * 1024u64),
+        "DMA buffer misaligned"
+    );
+    assert_eq!(free(), before - 16);
+
+    // SAFETY: the buffer is mapped through the physical memory window
+    let bytes =
+        unsafe { core::slice::from_raw_parts(buffer.as_mut_ptr::<u8>(), buffer.size() as usize) };
+    assert!(bytes.iter().all(|&b| b == 0), "DMA buffer not zeroed");
+    assert_eq!(buffer.virt(), VirtAddr::from_ptr(bytes.as_ptr()));
+
+    drop(buffer);
+    assert_eq!(free(), before, "DMA buffer not freed");
+}
+
+/// Leases frames and checks they are accounted for until the leases are released.
+fn leases(mem: &MemoryManager) {
+    let before = mem.frame_stats();
+
+    let leases: Vec<_> = mem.reserve_frames(64).collect();
+    let leased: usize = leases.iter().map(|lease| lease.frames().count()).sum();
+    assert_eq!(leased, 64, "frames missing from the leases");
+
+    let stats = mem.frame_stats();
+    assert_eq!(stats.leased, before.leased + 64);
+    assert_eq!(stats.free, before.free - 64);
+
+    for lease in leases {
+        lease.release();
+    }
+    let after = mem.frame_stats();
+    assert_eq!((after.leased, after.free), (before.leased, before.free));
+}
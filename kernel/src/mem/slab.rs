@@ -0,0 +1,387 @@
+use crate::mem::{frame_allocator, phys_offset};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinning_top::Spinlock;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Every slab is a single frame.
+const SLAB_SIZE: usize = 4096;
+const MAX_OBJECTS: usize = 512;
+const MAX_CACHES: usize = 32;
+
+/// Caches that have been used at least once, for statistics.
+static CACHES: Spinlock<[Option<&'static dyn CacheInfo>; MAX_CACHES]> =
+    Spinlock::new([None; MAX_CACHES]);
+
+/// Object cache for values of type `T`, carved out of whole frames.
+///
+/// The constructor runs for every object of a slab once the slab gets created.
+/// Freed objects go back into the cache as they are and are handed out again
+/// without running the constructor, so they have to be left in a reusable state.
+/// They are only dropped once their slab is empty and returned to the frame allocator.
+pub struct SlabCache<T: 'static> {
+    name: &'static str,
+    ctor: fn() -> T,
+
+    inner: Spinlock<CacheInner>,
+    registered: AtomicBool,
+
+    _marker: PhantomData<T>,
+}
+
+struct CacheInner {
+    /// Slabs with at least one free object.
+    partial: SlabList,
+    /// Slabs without free objects.
+    full: SlabList,
+
+    stats: SlabStats,
+}
+
+// SAFETY: the slabs are only touched while holding the lock
+unsafe impl Send for CacheInner {}
+
+// SAFETY: objects are handed out to one owner at a time, which may live on any thread
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SlabStats {
+    /// Slabs currently owned by the cache.
+    pub slabs: usize,
+    /// Objects handed out right now.
+    pub in_use: usize,
+    /// Objects all slabs together can hold.
+    pub capacity: usize,
+
+    pub allocs: u64,
+    pub frees: u64,
+    pub slabs_created: u64,
+    pub slabs_released: u64,
+}
+
+/// Header at the start of every slab, the objects follow it.
+#[repr(C)]
+struct Slab {
+    next: Option<NonNull<Slab>>,
+    prev: Option<NonNull<Slab>>,
+
+    used: usize,
+    /// Set bits mark objects that are handed out.
+    bitmap: [u64; MAX_OBJECTS / 64],
+}
+
+struct SlabList {
+    head: Option<NonNull<Slab>>,
+}
+
+/// An object owned by a `SlabCache`, it goes back to the cache when dropped.
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+trait CacheInfo: Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> SlabStats;
+}
+
+impl<T: 'static> SlabCache<T> {
+    /// Distance between two objects.
+    const STRIDE: usize = {
+        let size = if size_of::<T>() == 0 {
+            1
+        } else {
+            size_of::<T>()
+        };
+        size.next_multiple_of(align_of::<T>())
+    };
+
+    /// Offset of the first object from the start of the slab.
+    const OFFSET: usize = size_of::<Slab>().next_multiple_of(align_of::<T>());
+
+    const CAPACITY: usize = {
+        let capacity = (SLAB_SIZE - Self::OFFSET) / Self::STRIDE;
+        if capacity > MAX_OBJECTS {
+            MAX_OBJECTS
+        } else {
+            capacity
+        }
+    };
+
+    const FITS: () = assert!(
+        Self::OFFSET + Self::STRIDE <= SLAB_SIZE,
+        "type too large for a slab"
+    );
+
+    pub const fn new(name: &'static str, ctor: fn() -> T) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+
+        Self {
+            name,
+            ctor,
+            inner: Spinlock::new(CacheInner {
+                partial: SlabList { head: None },
+                full: SlabList { head: None },
+                stats: SlabStats {
+                    slabs: 0,
+                    in_use: 0,
+                    capacity: 0,
+                    allocs: 0,
+                    frees: 0,
+                    slabs_created: 0,
+                    slabs_released: 0,
+                },
+            }),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Hands out an object, `None` if no frame was left for a new slab.
+    pub fn alloc(&'static self) -> Option<SlabBox<T>>
+    where
+        T: Send,
+    {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            register(self);
+        }
+
+        let mut inner = self.inner.lock();
+        let slab = match inner.partial.head {
+            Some(slab) => slab,
+            None => {
+                // the constructor may take a while, don't hold the lock meanwhile
+                drop(inner);
+                let slab = self.grow()?;
+
+                inner = self.inner.lock();
+                // SAFETY: the slab was just created and belongs to this cache
+                unsafe { inner.partial.push(slab) };
+                inner.stats.slabs += 1;
+                inner.stats.capacity += Self::CAPACITY;
+                inner.stats.slabs_created += 1;
+                slab
+            }
+        };
+
+        // SAFETY: slabs in the lists belong to this cache and are only touched under the lock
+        let header = unsafe { &mut *slab.as_ptr() };
+        let index = (0..Self::CAPACITY)
+            .find(|&i| header.bitmap[i / 64] & (1 << (i % 64)) == 0)
+            .expect("partial slab without free objects");
+
+        header.bitmap[index / 64] |= 1 << (index % 64);
+        header.used += 1;
+
+        if header.used == Self::CAPACITY {
+            // SAFETY: the slab is in the partial list
+            unsafe {
+                inner.partial.remove(slab);
+                inner.full.push(slab);
+            }
+        }
+
+        inner.stats.in_use += 1;
+        inner.stats.allocs += 1;
+
+        Some(SlabBox {
+            ptr: Self::object(slab, index),
+            cache: self,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.inner.lock().stats
+    }
+
+    /// Takes a new frame and constructs all of its objects.
+    fn grow(&self) -> Option<NonNull<Slab>> {
+        let frames = frame_allocator()?.allocate_contiguous(1)?;
+        let start = phys_offset()? + frames.start.start_address().as_u64();
+        let slab = NonNull::new(start.as_mut_ptr::<Slab>()).unwrap();
+
+        // SAFETY: the frame was just allocated and is mapped through the physical memory offset
+        unsafe {
+            slab.as_ptr().write(Slab {
+                next: None,
+                prev: None,
+                used: 0,
+                bitmap: [0; MAX_OBJECTS / 64],
+            });
+
+            for index in 0..Self::CAPACITY {
+                Self::object(slab, index).as_ptr().write((self.ctor)());
+            }
+        }
+
+        Some(slab)
+    }
+
+    /// Takes back an object.
+    ///
+    /// # Safety
+    /// `ptr` has to come from this cache and may not be used anymore.
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        let slab = (ptr.as_ptr() as usize) & !(SLAB_SIZE - 1);
+        let index = (ptr.as_ptr() as usize - slab - Self::OFFSET) / Self::STRIDE;
+        let slab = NonNull::new(slab as *mut Slab).unwrap();
+
+        let mut inner = self.inner.lock();
+
+        // SAFETY: the object came from this cache, so its slab belongs to it as well
+        let header = unsafe { &mut *slab.as_ptr() };
+        assert_ne!(
+            header.bitmap[index / 64] & (1 << (index % 64)),
+            0,
+            "double free in slab cache {}",
+            self.name
+        );
+
+        let was_full = header.used == Self::CAPACITY;
+        header.bitmap[index / 64] &= !(1 << (index % 64));
+        header.used -= 1;
+
+        inner.stats.in_use -= 1;
+        inner.stats.frees += 1;
+
+        // SAFETY: the slab is in the list matching its fill level
+        unsafe {
+            if was_full {
+                inner.full.remove(slab);
+                inner.partial.push(slab);
+            }
+
+            if header.used == 0 {
+                inner.partial.remove(slab);
+                inner.stats.slabs -= 1;
+                inner.stats.capacity -= Self::CAPACITY;
+                inner.stats.slabs_released += 1;
+
+                drop(inner);
+                self.release(slab);
+            }
+        }
+    }
+
+    /// Drops all objects of an empty slab and gives its frame back.
+    ///
+    /// # Safety
+    /// The slab must not be in any list and none of its objects may be in use.
+    unsafe fn release(&self, slab: NonNull<Slab>) {
+        for index in 0..Self::CAPACITY {
+            // SAFETY: every object got constructed when the slab was created
+            unsafe { Self::object(slab, index).as_ptr().drop_in_place() };
+        }
+
+        let offset = phys_offset().expect("slabs exist only after the memory manager");
+        let frame = PhysFrame::containing_address(PhysAddr::new(
+            VirtAddr::from_ptr(slab.as_ptr()) - offset,
+        ));
+
+        let allocator = frame_allocator().expect("slabs exist only after the memory manager");
+        // SAFETY: the frame was allocated by `grow` and nothing refers to it anymore
+        unsafe { allocator.deallocate_range(PhysFrame::range(frame, frame + 1)) };
+    }
+
+    fn object(slab: NonNull<Slab>, index: usize) -> NonNull<T> {
+        let addr = slab.as_ptr() as usize + Self::OFFSET + index * Self::STRIDE;
+        NonNull::new(addr as *mut T).unwrap()
+    }
+}
+
+impl<T: Send + 'static> CacheInfo for SlabCache<T> {
+    fn name(&self) -> &'static str {
+        SlabCache::name(self)
+    }
+
+    fn stats(&self) -> SlabStats {
+        SlabCache::stats(self)
+    }
+}
+
+impl SlabList {
+    /// # Safety
+    /// The slab may not be in a list already.
+    unsafe fn push(&mut self, slab: NonNull<Slab>) {
+        // SAFETY: all slabs of a list are valid, guaranteed by the caller
+        unsafe {
+            (*slab.as_ptr()).prev = None;
+            (*slab.as_ptr()).next = self.head;
+            if let Some(head) = self.head {
+                (*head.as_ptr()).prev = Some(slab);
+            }
+        }
+
+        self.head = Some(slab);
+    }
+
+    /// # Safety
+    /// The slab has to be in this list.
+    unsafe fn remove(&mut self, slab: NonNull<Slab>) {
+        // SAFETY: all slabs of a list are valid, guaranteed by the caller
+        unsafe {
+            let Slab { next, prev, .. } = *slab.as_ptr();
+
+            match prev {
+                Some(prev) => (*prev.as_ptr()).next = next,
+                None => self.head = next,
+            }
+            if let Some(next) = next {
+                (*next.as_ptr()).prev = prev;
+            }
+        }
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the object is owned by this box
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the object is owned by this box
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: the object came from this cache and the box is gone now
+        unsafe { self.cache.free(self.ptr) };
+    }
+}
+
+// SAFETY: the box owns its object like a `Box` does
+unsafe impl<T: Send> Send for SlabBox<T> {}
+// SAFETY: the box owns its object like a `Box` does
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+fn register(cache: &'static dyn CacheInfo) {
+    let mut caches = CACHES.lock();
+
+    match caches.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => log::warn!("Too many slab caches, {} has no statistics", cache.name()),
+    }
+}
+
+/// Calls `f` with the name and statistics of every slab cache that has been used so far.
+pub fn for_each_cache(mut f: impl FnMut(&'static str, SlabStats)) {
+    for cache in CACHES.lock().iter().flatten() {
+        f(cache.name(), cache.stats());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::mem::kfalloc::KernelFrameAllocator;
+use crate::mem::{for_each_mapping, CacheMode, KernelImage, MapFlags};
+use core::arch::x86_64::__cpuid;
+use core::fmt::Debug;
+use core::ops::Range;
+use log::{debug, warn};
+use x86_64::registers::control::{Cr3, Cr4, Cr4Flags};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size1GiB, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where things end up in the kernel's own page tables.
+pub(super) struct KernelLayout<'a> {
+    pub phys_offset: VirtAddr,
+    /// End of physical memory, the window gets rounded up to whole huge pages.
+    pub phys_end: u64,
+    pub kernel: &'a KernelImage,
+    pub framebuffer: Option<Range<VirtAddr>>,
+}
+
+/// Builds a page table hierarchy owned by the kernel and switches CR3 to it.
+///
+/// The kernel's segments get mapped with the permissions from its ELF headers and
+/// the physical memory window with huge pages. Everything else the bootloader mapped,
+/// like the boot info, the ramdisk and the stack we are running on, is copied over,
+/// with the framebuffer becoming write-combining.
+///
+/// # Safety
+/// `old` has to be the active hierarchy and nothing but the bootloader may have mapped
+/// anything yet. The PAT has to be set up already.
+pub(super) unsafe fn switch_to_kernel_tables(
+    old: &OffsetPageTable<'static>,
+    layout: KernelLayout,
+    mut allocator: &'static KernelFrameAllocator,
+) -> OffsetPageTable<'static> {
+    let root: PhysFrame = allocator
+        .allocate_frame()
+        .expect("no frame left for the kernel's level 4 table");
+
+    // SAFETY: the frame was just allocated and the window maps all of physical memory
+    let p4 = unsafe {
+        &mut *(layout.phys_offset + root.start_address().as_u64()).as_mut_ptr::<PageTable>()
+    };
+    p4.zero();
+
+    // SAFETY: same as above
+    let mut tables = unsafe { OffsetPageTable::new(p4, layout.phys_offset) };
+
+    let window = map_phys_window(&mut tables, &layout, &mut allocator);
+    map_kernel(&mut tables, old, layout.kernel, &mut allocator);
+
+    let image = layout.kernel.span();
+    let image = image.start.align_down(4096u64)..image.end.align_up(4096u64);
+    copy_mappings(
+        &mut tables,
+        &[window, image],
+        layout.framebuffer,
+        &mut allocator,
+    );
+
+    // SAFETY: everything we still use is mapped in the new tables, at the same addresses
+    unsafe { activate(root) };
+
+    debug!(
+        "Switched to the kernel's page tables at {:?}",
+        root.start_address()
+    );
+    tables
+}
+
+/// Maps all of physical memory at the offset with the largest pages available.
+///
+/// Returns the virtual range of the window.
+fn map_phys_window(
+    tables: &mut OffsetPageTable<'static>,
+    layout: &KernelLayout,
+    allocator: &mut &'static KernelFrameAllocator,
+) -> Range<VirtAddr> {
+    let offset = layout.phys_offset;
+
+    let size = if offset.is_aligned(Size1GiB::SIZE) && has_1gib_pages() {
+        map_window::<Size1GiB>(tables, offset, layout.phys_end, allocator)
+    } else {
+        assert!(
+            offset.is_aligned(Size2MiB::SIZE),
+            "physical memory offset {offset:?} is not aligned to 2MiB"
+        );
+        map_window::<Size2MiB>(tables, offset, layout.phys_end, allocator)
+    };
+
+    offset..offset + size
+}
+
+fn map_window<S: PageSize + Debug>(
+    tables: &mut OffsetPageTable<'static>,
+    offset: VirtAddr,
+    end: u64,
+    allocator: &mut &'static KernelFrameAllocator,
+) -> u64
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let size = end.next_multiple_of(S::SIZE);
+    let flags = MapFlags::DATA.to_pte();
+
+    for phys in (0..size).step_by(S::SIZE as usize) {
+        map::<S>(tables, offset + phys, PhysAddr::new(phys), flags, allocator);
+    }
+
+    size
+}
+
+/// Maps the kernel's segments to the frames the bootloader loaded them into.
+fn map_kernel(
+    tables: &mut OffsetPageTable<'static>,
+    old: &OffsetPageTable<'static>,
+    kernel: &KernelImage,
+    allocator: &mut &'static KernelFrameAllocator,
+) {
+    for seg in kernel.segments().filter(|seg| seg.size > 0) {
+        if seg.writable() && seg.executable() {
+            warn!(
+                "Kernel segment at {:?} is writable and executable",
+                seg.start
+            );
+        }
+
+        let flags = MapFlags {
+            writable: seg.writable(),
+            no_execute: !seg.executable(),
+            ..MapFlags::DATA
+        };
+
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(seg.start),
+            Page::containing_address(seg.end() - 1u64),
+        );
+
+        for page in pages {
+            let addr = page.start_address();
+
+            if let TranslateResult::Mapped { flags: shared, .. } = tables.translate(addr) {
+                // the page is shared with the previous segment, grant what both need
+                let shared = MapFlags::from_pte(shared);
+                let flags = MapFlags {
+                    writable: flags.writable || shared.writable,
+                    no_execute: flags.no_execute && shared.no_execute,
+                    ..flags
+                };
+
+                // SAFETY: only the permissions of the kernel's own page change
+                unsafe { tables.update_flags(page, flags.to_pte()) }
+                    .expect("kernel page vanished")
+                    .ignore();
+                continue;
+            }
+
+            let phys = old
+                .translate_addr(addr)
+                .expect("kernel segment not mapped by the bootloader");
+            map::<Size4KiB>(tables, addr, phys, flags.to_pte(), allocator);
+        }
+    }
+}
+
+/// Copies everything mapped by the active tables outside of `skip`.
+fn copy_mappings(
+    tables: &mut OffsetPageTable<'static>,
+    skip: &[Range<VirtAddr>],
+    framebuffer: Option<Range<VirtAddr>>,
+    allocator: &mut &'static KernelFrameAllocator,
+) {
+    for_each_mapping(|range| {
+        for offset in (0..range.size).step_by(range.page_size as usize) {
+            let virt = range.start + offset;
+            let phys = range.phys + offset;
+
+            if skip.iter().any(|skip| skip.contains(&virt)) {
+                continue;
+            }
+
+            let flags = match &framebuffer {
+                Some(fb) if fb.contains(&virt) => range.flags.with_cache(CacheMode::WriteCombining),
+                _ => range.flags,
+            };
+
+            match range.page_size {
+                0x1000 => map::<Size4KiB>(tables, virt, phys, flags.to_pte(), allocator),
+                0x20_0000 => map::<Size2MiB>(tables, virt, phys, flags.to_pte(), allocator),
+                _ => map::<Size1GiB>(tables, virt, phys, flags.to_pte(), allocator),
+            }
+        }
+    });
+}
+
+fn map<S: PageSize + Debug>(
+    tables: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    allocator: &mut &'static KernelFrameAllocator,
+) where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+
+    // SAFETY: the tables are not active yet, what they map only matters once we switch
+    match unsafe { tables.map_to(page, frame, flags, allocator) } {
+        Ok(flush) => flush.ignore(),
+        Err(err) => panic!("failed to map {page:?} in the kernel's page tables: {err:?}"),
+    }
+}
+
+/// Loads `root` into CR3 and drops every TLB entry of the old tables.
+///
+/// # Safety
+/// The new tables have to map everything in use.
+unsafe fn activate(root: PhysFrame) {
+    let (_, flags) = Cr3::read();
+
+    // SAFETY: guaranteed by the caller
+    unsafe { Cr3::write(root, flags) };
+
+    // global entries survive the CR3 write, toggling PGE flushes them as well
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        // SAFETY: only PGE changes and it is restored right away
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    }
+}
+
+fn has_1gib_pages() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
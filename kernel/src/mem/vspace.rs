@@ -0,0 +1,315 @@
+use crate::mem::{CacheMode, MapFlags, MemoryManager};
+use core::fmt::{Display, Formatter};
+use log::warn;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{Page, PageTable, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Higher half of the address space, the kernel only hands out addresses from here.
+const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+const KERNEL_SPACE_END: u64 = 0xFFFF_FFFF_FFFF_F000;
+
+/// Virtual memory covered by a single level 4 entry.
+const P4_ENTRY_SIZE: u64 = 1 << 39;
+
+const MAX_HOLES: usize = 128;
+
+/// Free ranges of the kernel's virtual address space.
+///
+/// Kept as a sorted array of holes, as it is needed before there is a heap.
+/// Neighbouring holes are always merged.
+pub struct VirtualSpace {
+    holes: [Hole; MAX_HOLES],
+    len: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Hole {
+    start: u64,
+    end: u64,
+}
+
+/// A kernel stack with an unmapped guard page below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    guard: Page,
+    pages: PageRange,
+}
+
+#[derive(Debug)]
+pub enum RegionError {
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl VirtualSpace {
+    /// The whole higher half, minus every level 4 entry `p4` already uses.
+    pub fn new(p4: &PageTable) -> Self {
+        let mut this = Self {
+            holes: [Hole { start: 0, end: 0 }; MAX_HOLES],
+            len: 1,
+        };
+        this.holes[0] = Hole {
+            start: KERNEL_SPACE_START,
+            end: KERNEL_SPACE_END,
+        };
+
+        for (index, entry) in p4.iter().enumerate().skip(256) {
+            if !entry.is_unused() {
+                let start = VirtAddr::new_truncate(index as u64 * P4_ENTRY_SIZE);
+                this.reserve(start, P4_ENTRY_SIZE);
+            }
+        }
+
+        this
+    }
+
+    /// Marks `start..start + size` as used, no matter whether parts of it already are.
+    pub fn reserve(&mut self, start: VirtAddr, size: u64) {
+        let start = start.align_down(4096u64).as_u64();
+        let end = start
+            .saturating_add(size)
+            .min(KERNEL_SPACE_END)
+            .next_multiple_of(4096);
+
+        let mut i = 0;
+        while i < self.len {
+            let hole = self.holes[i];
+            if hole.end <= start || end <= hole.start {
+                i += 1;
+                continue;
+            }
+
+            match (hole.start < start, end < hole.end) {
+                (false, false) => {
+                    self.remove(i);
+                    continue;
+                }
+                (true, false) => self.holes[i].end = start,
+                (false, true) => self.holes[i].start = end,
+                (true, true) => {
+                    self.holes[i].end = start;
+                    if !self.insert(i + 1, Hole { start: end, ..hole }) {
+                        warn!(
+                            "Virtual space too fragmented, lost 0x{end:X}..0x{:X}",
+                            hole.end
+                        );
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Takes `pages` pages starting at a multiple of `align` bytes (first fit).
+    pub fn alloc(&mut self, pages: u64, align: u64) -> Option<PageRange> {
+        assert!(align.is_power_of_two() && align >= 4096);
+        let size = pages.checked_mul(4096)?;
+
+        let (index, start) =
+            self.holes[..self.len]
+                .iter()
+                .enumerate()
+                .find_map(|(index, hole)| {
+                    let start = hole.start.checked_next_multiple_of(align)?;
+                    (start.checked_add(size)? <= hole.end).then_some((index, start))
+                })?;
+
+        let hole = self.holes[index];
+        let end = start + size;
+
+        if hole.start < start && end < hole.end {
+            // splitting needs a second slot
+            if !self.insert(index + 1, Hole { start: end, ..hole }) {
+                return None;
+            }
+            self.holes[index].end = start;
+        } else if hole.start < start {
+            self.holes[index].end = start;
+        } else if end < hole.end {
+            self.holes[index].start = end;
+        } else {
+            self.remove(index);
+        }
+
+        Some(page_range(start, end))
+    }
+
+    /// Gives back a range handed out by `alloc`.
+    pub fn free(&mut self, range: PageRange) {
+        let start = range.start.start_address().as_u64();
+        let end = range.end.start_address().as_u64();
+        if start == end {
+            return;
+        }
+
+        let index = self.holes[..self.len].partition_point(|hole| hole.end <= start);
+        if let Some(next) = self.holes[..self.len].get(index) {
+            assert!(
+                end <= next.start,
+                "double free of virtual range 0x{start:X}"
+            );
+        }
+
+        let merge_prev = index > 0 && self.holes[index - 1].end == start;
+        let merge_next = index < self.len && self.holes[index].start == end;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.holes[index - 1].end = self.holes[index].end;
+                self.remove(index);
+            }
+            (true, false) => self.holes[index - 1].end = end,
+            (false, true) => self.holes[index].start = start,
+            (false, false) => {
+                if !self.insert(index, Hole { start, end }) {
+                    warn!("Virtual space too fragmented, lost 0x{start:X}..0x{end:X}");
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, index: usize, hole: Hole) -> bool {
+        if self.len == MAX_HOLES {
+            return false;
+        }
+
+        self.holes.copy_within(index..self.len, index + 1);
+        self.holes[index] = hole;
+        self.len += 1;
+        true
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.holes.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+}
+
+impl KernelStack {
+    /// Initial stack pointer, the stack grows down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.pages.end.start_address()
+    }
+
+    pub fn guard(&self) -> Page {
+        self.guard
+    }
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::OutOfVirtualSpace => write!(f, "out of kernel address space"),
+            Self::Map(err) => write!(f, "mapping failed with {err:?}"),
+        }
+    }
+}
+
+impl MemoryManager {
+    /// Reserves `pages` pages of kernel address space starting at a multiple of `align` bytes.
+    ///
+    /// Nothing gets mapped, the range is just guaranteed to not be handed out twice.
+    pub fn reserve_virtual(&self, pages: u64, align: u64) -> Option<PageRange> {
+        self.vspace.lock().alloc(pages, align)
+    }
+
+    /// Gives back address space from `reserve_virtual`.
+    ///
+    /// # Safety
+    /// Nothing may be mapped in `range` anymore.
+    pub unsafe fn release_virtual(&self, range: PageRange) {
+        self.vspace.lock().free(range);
+    }
+
+    /// Allocates a stack of `pages` pages with a guard page below it.
+    pub fn alloc_stack(&self, pages: u64) -> Result<KernelStack, RegionError> {
+        let range = self
+            .reserve_virtual(pages + 1, 4096)
+            .ok_or(RegionError::OutOfVirtualSpace)?;
+
+        let stack = KernelStack {
+            guard: range.start,
+            pages: Page::range(range.start + 1, range.end),
+        };
+
+        if let Err(err) = self.map_anonymous(stack.pages, MapFlags::DATA) {
+            // SAFETY: nothing got mapped
+            unsafe { self.release_virtual(range) };
+            return Err(RegionError::Map(err));
+        }
+
+        Ok(stack)
+    }
+
+    /// # Safety
+    /// The stack may not be in use anymore.
+    pub unsafe fn free_stack(&self, stack: KernelStack) {
+        // SAFETY: guaranteed by the caller, the frames got mapped by `alloc_stack`
+        unsafe {
+            self.unmap_anonymous(stack.pages)
+                .expect("stacks only consist of 4KiB pages");
+            self.release_virtual(Page::range(stack.guard, stack.pages.end));
+        }
+    }
+
+    /// Maps `size` bytes of device memory at `phys` and returns where they ended up.
+    ///
+    /// # Safety
+    /// The memory must belong to a device, mapping RAM with another cache mode is undefined.
+    pub unsafe fn map_mmio(
+        &self,
+        phys: PhysAddr,
+        size: u64,
+        cache: CacheMode,
+    ) -> Result<VirtAddr, RegionError> {
+        let first = PhysFrame::containing_address(phys);
+        let last = PhysFrame::containing_address(phys + size.max(1) - 1u64);
+        let frames = PhysFrameRange {
+            start: first,
+            end: last + 1,
+        };
+
+        let pages = self
+            .reserve_virtual(frames.count() as u64, 4096)
+            .ok_or(RegionError::OutOfVirtualSpace)?;
+
+        // SAFETY: guaranteed by the caller
+        let result = unsafe { self.map_phys(pages, frames, MapFlags::MMIO.with_cache(cache)) };
+        if let Err(err) = result {
+            // SAFETY: nothing got mapped
+            unsafe { self.release_virtual(pages) };
+            return Err(RegionError::Map(err));
+        }
+
+        Ok(pages.start.start_address() + (phys - first.start_address()))
+    }
+
+    /// Unmaps device memory set up by `map_mmio`.
+    ///
+    /// # Safety
+    /// `addr` and `size` have to be the same as for `map_mmio`, nothing may access the memory anymore.
+    pub unsafe fn unmap_mmio(&self, addr: VirtAddr, size: u64) {
+        let pages = Page::range_inclusive(
+            Page::containing_address(addr),
+            Page::containing_address(addr + size.max(1) - 1u64),
+        );
+        let pages = Page::range(pages.start, pages.end + 1);
+
+        // SAFETY: guaranteed by the caller
+        unsafe {
+            self.unmap(pages)
+                .expect("mmio is only mapped with 4KiB pages");
+            self.release_virtual(pages);
+        }
+    }
+}
+
+fn page_range(start: u64, end: u64) -> PageRange {
+    Page::range(
+        Page::containing_address(VirtAddr::new(start)),
+        Page::containing_address(VirtAddr::new(end)),
+    )
+}
@@ -1,2 +0,0 @@
-mod list;
-mod stage1;
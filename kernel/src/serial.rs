@@ -6,7 +6,7 @@ use uart_16550::SerialPort;
 
 // referencing https://wiki.osdev.org/Serial_Ports
 
-pub static COM1: Lazy<SharedSerialPort> = Lazy::new(|| SharedSerialPort::init());
+pub static COM1: Lazy<SharedSerialPort> = Lazy::new(SharedSerialPort::init);
 const COM1_PORT: u16 = 0x3F8;
 
 pub struct SharedSerialPort(Spinlock<SerialPort>);
@@ -18,6 +18,11 @@ impl SharedSerialPort {
 
         Self(Spinlock::new(port))
     }
+
+    /// Writes `s` only if the lock is free, returns `None` otherwise.
+    pub fn try_write_str(&self, s: &str) -> Option<core::fmt::Result> {
+        Some(self.0.try_lock()?.write_str(s))
+    }
 }
 
 impl Write for &SharedSerialPort {
@@ -25,3 +30,32 @@ impl Write for &SharedSerialPort {
         self.0.lock().write_str(s)
     }
 }
+
+/// Writes to COM1 without ever taking a lock.
+///
+/// Output may interleave with whoever holds the `COM1` lock,
+/// only meant for when that lock can not be trusted anymore.
+pub struct RawSerial;
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // SAFETY: COM1 is always present and either initialized by `COM1` or usable with defaults
+        let mut port = unsafe { SerialPort::new(COM1_PORT) };
+        port.write_str(s)
+    }
+}
+
+/// Writes to COM1, bypassing the lock when it is held.
+pub struct EmergencySerial;
+
+impl Write for EmergencySerial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if Lazy::is_initialized(&COM1) {
+            if let Some(res) = COM1.try_write_str(s) {
+                return res;
+            }
+        }
+
+        RawSerial.write_str(s)
+    }
+}
@@ -0,0 +1,519 @@
+//! Minimal `.debug_line` interpreter used to resolve addresses to source lines.
+//!
+//! Only the parts of DWARF 2-5 needed to map an address to `file:line` are
+//! implemented, everything else is skipped.
+
+use conquer_once::spin::OnceCell;
+use core::fmt::{Display, Formatter};
+use log::{info, warn};
+
+static DEBUG_INFO: OnceCell<DebugInfo> = OnceCell::uninit();
+
+struct DebugInfo {
+    debug_line: &'static [u8],
+    debug_line_str: &'static [u8],
+    debug_str: &'static [u8],
+
+    /// Offset the kernel image was loaded at, relative to the ELF addresses.
+    image_offset: u64,
+}
+
+#[derive(Copy, Clone)]
+pub struct Location {
+    pub file: &'static str,
+    pub line: u64,
+}
+
+/// Locates the DWARF sections in the kernel ELF.
+///
+/// # Safety
+/// `elf` must be the kernel file as loaded by the bootloader, and stay mapped forever.
+pub unsafe fn init_debug_info(elf: &'static [u8], image_offset: u64) {
+    let Some(debug_line) = elf_section(elf, ".debug_line") else {
+        warn!("Kernel has no .debug_line, stack traces will not have line numbers");
+        return;
+    };
+
+    info!("Found .debug_line ({} bytes)", debug_line.len());
+
+    DEBUG_INFO.init_once(|| DebugInfo {
+        debug_line,
+        debug_line_str: elf_section(elf, ".debug_line_str").unwrap_or(&[]),
+        debug_str: elf_section(elf, ".debug_str").unwrap_or(&[]),
+        image_offset,
+    });
+}
+
+/// Resolves `addr` to the source line it was generated from.
+pub fn lookup_line(addr: u64) -> Option<Location> {
+    let info = DEBUG_INFO.try_get().ok()?;
+    let addr = addr.checked_sub(info.image_offset)?;
+
+    let mut offset = 0;
+    while offset < info.debug_line.len() {
+        let unit = LineUnit::parse(info, offset)?;
+        offset = unit.end;
+
+        if let Some(loc) = unit.find(addr) {
+            return Some(loc);
+        }
+    }
+
+    None
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Returns the contents of the section called `name`.
+fn elf_section(elf: &'static [u8], name: &str) -> Option<&'static [u8]> {
+    let mut r = Reader::at(elf, 0);
+    if r.bytes(4)? != b"\x7fELF" || r.u8()? != 2 {
+        // not a 64-bit ELF
+        return None;
+    }
+
+    let shoff = Reader::at(elf, 0x28).u64()? as usize;
+    let shentsize = Reader::at(elf, 0x3A).u16()? as usize;
+    let shnum = Reader::at(elf, 0x3C).u16()? as usize;
+    let shstrndx = Reader::at(elf, 0x3E).u16()? as usize;
+
+    // (name, offset, size)
+    let header = |index: usize| -> Option<(usize, usize, usize)> {
+        let base = shoff + index * shentsize;
+        Some((
+            Reader::at(elf, base).u32()? as usize,
+            Reader::at(elf, base + 24).u64()? as usize,
+            Reader::at(elf, base + 32).u64()? as usize,
+        ))
+    };
+
+    let (_, strtab_off, strtab_size) = header(shstrndx)?;
+    let strtab = elf.get(strtab_off..strtab_off + strtab_size)?;
+
+    for index in 0..shnum {
+        let (name_off, off, size) = header(index)?;
+        if Reader::at(strtab, name_off).cstr()? == name {
+            return elf.get(off..off + size);
+        }
+    }
+
+    None
+}
+
+/// A single line number program with its header.
+struct LineUnit {
+    info: &'static DebugInfo,
+
+    version: u16,
+    offset_size: usize,
+    min_inst_len: u8,
+    line_base: i8,
+    line_range: u8,
+    opcode_base: u8,
+    std_opcode_lengths: &'static [u8],
+
+    /// Start of the file table (v2-4) or of the file entry formats (v5).
+    files: usize,
+    program: usize,
+    end: usize,
+}
+
+#[derive(Copy, Clone)]
+struct Row {
+    addr: u64,
+    file: u64,
+    line: u64,
+}
+
+impl LineUnit {
+    fn parse(info: &'static DebugInfo, offset: usize) -> Option<Self> {
+        let data = info.debug_line;
+        let mut r = Reader::at(data, offset);
+
+        let (unit_length, offset_size) = match r.u32()? {
+            0xFFFF_FFFF => (r.u64()? as usize, 8),
+            len => (len as usize, 4),
+        };
+        let end = r.pos.checked_add(unit_length)?;
+
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            let _address_size = r.u8()?;
+            let _segment_selector_size = r.u8()?;
+        }
+
+        let header_length = r.offset(offset_size)? as usize;
+        let program = r.pos.checked_add(header_length)?;
+
+        let min_inst_len = r.u8()?;
+        if version >= 4 {
+            let _max_ops_per_inst = r.u8()?;
+        }
+        let _default_is_stmt = r.u8()?;
+        let line_base = r.u8()? as i8;
+        let line_range = r.u8()?;
+        if line_range == 0 {
+            return None;
+        }
+        let opcode_base = r.u8()?;
+        let std_opcode_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?;
+
+        let mut unit = Self {
+            info,
+            version,
+            offset_size,
+            min_inst_len,
+            line_base,
+            line_range,
+            opcode_base,
+            std_opcode_lengths,
+            files: 0,
+            program,
+            end,
+        };
+
+        if version >= 5 {
+            // skip the directory table, we only print file names
+            let formats = r.pos;
+            let format_count = r.u8()?;
+            for _ in 0..format_count {
+                r.uleb()?;
+                r.uleb()?;
+            }
+            let dir_count = r.uleb()?;
+            for _ in 0..dir_count {
+                let mut format = Reader::at(data, formats + 1);
+                for _ in 0..format_count {
+                    let _content = format.uleb()?;
+                    unit.skip_form(&mut r, format.uleb()?)?;
+                }
+            }
+        } else {
+            // include_directories, terminated by an empty string
+            while !r.cstr()?.is_empty() {}
+        }
+
+        unit.files = r.pos;
+        Some(unit)
+    }
+
+    /// Runs the line program until a row covering `addr` is found.
+    fn find(&self, addr: u64) -> Option<Location> {
+        let mut r = Reader::at(self.info.debug_line, self.program);
+
+        let initial = Row {
+            addr: 0,
+            file: 1,
+            line: 1,
+        };
+        let mut row = initial;
+        let mut prev: Option<Row> = None;
+
+        // returns the previous row if it covers `addr`
+        let check = |prev: &Option<Row>, row: &Row| -> Option<Row> {
+            prev.filter(|p| p.addr <= addr && addr < row.addr)
+        };
+
+        while r.pos < self.end {
+            let opcode = r.u8()?;
+
+            if opcode >= self.opcode_base {
+                let adjusted = opcode - self.opcode_base;
+                let advance = (adjusted / self.line_range) as u64 * self.min_inst_len as u64;
+                row.addr = row.addr.wrapping_add(advance);
+                row.line = row.line.wrapping_add_signed(
+                    self.line_base as i64 + (adjusted % self.line_range) as i64,
+                );
+
+                if let Some(found) = check(&prev, &row) {
+                    return self.location(found);
+                }
+                prev = Some(row);
+                continue;
+            }
+
+            match opcode {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.pos.checked_add(len)?;
+
+                    match r.u8()? {
+                        // DW_LNE_end_sequence
+                        1 => {
+                            if let Some(found) = check(&prev, &row) {
+                                return self.location(found);
+                            }
+                            row = initial;
+                            prev = None;
+                        }
+                        // DW_LNE_set_address
+                        2 => row.addr = r.u64()?,
+                        _ => (),
+                    }
+
+                    r.pos = next;
+                }
+                // DW_LNS_copy
+                1 => {
+                    if let Some(found) = check(&prev, &row) {
+                        return self.location(found);
+                    }
+                    prev = Some(row);
+                }
+                // DW_LNS_advance_pc
+                2 => {
+                    let advance = r.uleb()?.wrapping_mul(self.min_inst_len as u64);
+                    row.addr = row.addr.wrapping_add(advance);
+                }
+                // DW_LNS_advance_line
+                3 => row.line = row.line.wrapping_add_signed(r.sleb()?),
+                // DW_LNS_set_file
+                4 => row.file = r.uleb()?,
+                // DW_LNS_const_add_pc
+                8 => {
+                    let adjusted = 255 - self.opcode_base;
+                    let advance = (adjusted / self.line_range) as u64 * self.min_inst_len as u64;
+                    row.addr = row.addr.wrapping_add(advance);
+                }
+                // DW_LNS_fixed_advance_pc
+                9 => row.addr = row.addr.wrapping_add(r.u16()? as u64),
+                // everything else only takes uleb arguments we do not care about
+                _ => {
+                    for _ in 0..self.std_opcode_lengths[opcode as usize - 1] {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn location(&self, row: Row) -> Option<Location> {
+        // line 0 is used for code that can not be attributed to any source line
+        if row.line == 0 {
+            return None;
+        }
+
+        Some(Location {
+            file: self.file_name(row.file).unwrap_or("<unknown>"),
+            line: row.line,
+        })
+    }
+
+    fn file_name(&self, index: u64) -> Option<&'static str> {
+        let data = self.info.debug_line;
+        let mut r = Reader::at(data, self.files);
+
+        if self.version < 5 {
+            // file indices start at 1 before DWARF 5
+            let mut current = 1;
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    return None;
+                }
+                if current == index {
+                    return Some(name);
+                }
+
+                // directory index, modification time, length
+                r.uleb()?;
+                r.uleb()?;
+                r.uleb()?;
+                current += 1;
+            }
+        }
+
+        let formats = r.pos;
+        let format_count = r.u8()?;
+        for _ in 0..format_count {
+            r.uleb()?;
+            r.uleb()?;
+        }
+
+        let file_count = r.uleb()?;
+        for current in 0..file_count {
+            let mut format = Reader::at(data, formats + 1);
+            let mut name = None;
+
+            for _ in 0..format_count {
+                let content = format.uleb()?;
+                let form = format.uleb()?;
+
+                // DW_LNCT_path
+                if content == 1 {
+                    name = self.read_string_form(&mut r, form);
+                } else {
+                    self.skip_form(&mut r, form)?;
+                }
+            }
+
+            if current == index {
+                return name;
+            }
+        }
+
+        None
+    }
+
+    fn read_string_form(&self, r: &mut Reader, form: u64) -> Option<&'static str> {
+        match form {
+            // DW_FORM_string
+            0x08 => r.cstr(),
+            // DW_FORM_line_strp
+            0x1F => Reader::at(
+                self.info.debug_line_str,
+                r.offset(self.offset_size)? as usize,
+            )
+            .cstr(),
+            // DW_FORM_strp
+            0x0E => Reader::at(self.info.debug_str, r.offset(self.offset_size)? as usize).cstr(),
+            _ => {
+                self.skip_form(r, form)?;
+                None
+            }
+        }
+    }
+
+    fn skip_form(&self, r: &mut Reader, form: u64) -> Option<()> {
+        match form {
+            // DW_FORM_string
+            0x08 => {
+                r.cstr()?;
+            }
+            // DW_FORM_line_strp, DW_FORM_strp, DW_FORM_sec_offset
+            0x1F | 0x0E | 0x17 => {
+                r.offset(self.offset_size)?;
+            }
+            // DW_FORM_udata
+            0x0F => {
+                r.uleb()?;
+            }
+            // DW_FORM_data1, DW_FORM_strx1
+            0x0B | 0x25 => r.skip(1)?,
+            // DW_FORM_data2, DW_FORM_strx2
+            0x05 | 0x26 => r.skip(2)?,
+            // DW_FORM_strx3
+            0x27 => r.skip(3)?,
+            // DW_FORM_data4, DW_FORM_strx4
+            0x06 | 0x28 => r.skip(4)?,
+            // DW_FORM_data8
+            0x07 => r.skip(8)?,
+            // DW_FORM_data16
+            0x1E => r.skip(16)?,
+            // DW_FORM_block
+            0x09 => {
+                let len = r.uleb()? as usize;
+                r.skip(len)?;
+            }
+            // DW_FORM_strx
+            0x1A => {
+                r.uleb()?;
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+struct Reader {
+    data: &'static [u8],
+    pos: usize,
+}
+
+impl Reader {
+    fn at(data: &'static [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'static [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.data.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+
+    /// Moves past `len` bytes without reading them.
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.pos = self.pos.checked_add(len)?;
+        Some(())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    /// Section offset, 4 bytes in 32-bit DWARF and 8 bytes in 64-bit DWARF.
+    fn offset(&mut self, size: usize) -> Option<u64> {
+        match size {
+            8 => self.u64(),
+            _ => self.u32().map(u64::from),
+        }
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut result = 0i64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'static str> {
+        let rest = self.data.get(self.pos..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        self.pos += len + 1;
+
+        core::str::from_utf8(&rest[..len]).ok()
+    }
+}
@@ -0,0 +1,158 @@
+mod dwarf;
+mod symbols;
+
+pub use dwarf::{init_debug_info, Location};
+pub use symbols::{init_symbols, Symbol};
+
+use crate::gdt::on_ist_stack;
+use crate::STACK_END;
+use core::arch::asm;
+use core::fmt::{Display, Formatter};
+
+use log::trace;
+
+#[cfg(feature = "heap-debug")]
+/// Frames kept by a `Backtrace`.
+const BACKTRACE_DEPTH: usize = 12;
+
+/// A single resolved entry of a stack trace.
+pub struct Frame {
+    /// Return address of the frame.
+    pub rip: u64,
+    pub symbol: Option<Symbol>,
+    pub location: Option<Location>,
+}
+
+// SAFETY: stack frame pointers must be enabled
+#[inline(always)]
+pub unsafe fn dump_stack() {
+    trace!("Stack trace:");
+
+    unsafe {
+        walk_stack(|frame| trace!("    {frame}"));
+    }
+}
+
+/// Calls `f` for every frame on the stack, starting at the caller.
+///
+/// Does not lock or log anything, so it is safe to use while panicking.
+///
+/// # Safety
+/// Stack frame pointers must be enabled
+#[inline(always)]
+pub unsafe fn walk_stack(mut f: impl FnMut(&Frame)) {
+    unsafe { walk_return_addresses(|rip| f(&Frame::resolve(rip))) }
+}
+
+/// Like `walk_stack`, but without looking up symbols.
+///
+/// # Safety
+/// Stack frame pointers must be enabled
+#[inline(always)]
+unsafe fn walk_return_addresses(mut f: impl FnMut(u64)) {
+    let mut rbp: *const u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+    }
+
+    loop {
+        if rbp.is_null() {
+            break;
+        } else if rbp as u64 >= unsafe { STACK_END } && !on_ist_stack(rbp as u64) {
+            // found end or over stepped
+            // the IST stacks are statics and may lie above the boot stack,
+            // frames on them would otherwise end the walk right away
+            break;
+        }
+
+        f(unsafe { rbp.add(1).read() });
+
+        rbp = unsafe { *rbp as *const u64 };
+    }
+}
+
+impl Frame {
+    fn resolve(rip: u64) -> Self {
+        // rip is the return address, the call itself is the instruction before it
+        let call = rip.saturating_sub(1);
+
+        Frame {
+            rip,
+            symbol: symbols::lookup(call).map(|sym| Symbol {
+                offset: sym.offset + 1,
+                ..sym
+            }),
+            location: dwarf::lookup_line(call),
+        }
+    }
+}
+
+#[cfg(feature = "heap-debug")]
+/// Return addresses of the innermost frames, recorded to be symbolized later.
+#[derive(Copy, Clone)]
+pub struct Backtrace {
+    rips: [u64; BACKTRACE_DEPTH],
+    len: usize,
+}
+
+#[cfg(feature = "heap-debug")]
+impl Backtrace {
+    pub const fn empty() -> Self {
+        Self {
+            rips: [0; BACKTRACE_DEPTH],
+            len: 0,
+        }
+    }
+
+    /// Records the stack of the caller, without locking or allocating anything.
+    ///
+    /// # Safety
+    /// Stack frame pointers must be enabled
+    #[inline(always)]
+    pub unsafe fn capture() -> Self {
+        let mut this = Self::empty();
+
+        unsafe {
+            walk_return_addresses(|rip| {
+                if this.len < BACKTRACE_DEPTH {
+                    this.rips[this.len] = rip;
+                    this.len += 1;
+                }
+            })
+        };
+
+        this
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = Frame> + '_ {
+        self.rips[..self.len].iter().map(|&rip| Frame::resolve(rip))
+    }
+}
+
+#[cfg(feature = "heap-debug")]
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for frame in self.frames() {
+            writeln!(f, "    {frame}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:#018x} ", self.rip)?;
+
+        match &self.symbol {
+            Some(sym) => write!(f, "{sym}")?,
+            None => write!(f, "<unknown>")?,
+        }
+
+        if let Some(loc) = &self.location {
+            write!(f, " at {loc}")?;
+        }
+
+        Ok(())
+    }
+}
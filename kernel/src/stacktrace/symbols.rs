@@ -0,0 +1,118 @@
+use conquer_once::spin::OnceCell;
+use core::fmt::{Display, Formatter};
+use log::{info, warn};
+
+// The table is produced by `write_symbol_map` in the top level `build.rs`
+// and handed to us by the bootloader as the ramdisk.
+const MAGIC: &[u8; 4] = b"DSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 24;
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::uninit();
+
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    count: usize,
+
+    /// Offset the kernel image was loaded at, relative to the ELF addresses.
+    image_offset: u64,
+}
+
+#[derive(Copy, Clone)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+/// Registers the symbol table for use by `dump_stack`.
+///
+/// # Safety
+/// `data` must point to the symbol table ramdisk which stays mapped forever.
+pub unsafe fn init_symbols(data: &'static [u8], image_offset: u64) {
+    let Some(table) = SymbolTable::parse(data, image_offset) else {
+        warn!("Invalid symbol table, stack traces will not be symbolized");
+        return;
+    };
+
+    info!("Loaded {} kernel symbols", table.count);
+    SYMBOLS.init_once(|| table);
+}
+
+/// Finds the function containing `addr`.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    SYMBOLS.try_get().ok()?.lookup(addr)
+}
+
+impl SymbolTable {
+    fn parse(data: &'static [u8], image_offset: u64) -> Option<Self> {
+        if data.get(..4)? != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(data, 4)? as usize;
+        let names_start = HEADER_SIZE + count * ENTRY_SIZE;
+
+        Some(Self {
+            entries: data.get(HEADER_SIZE..names_start)?,
+            names: data.get(names_start..)?,
+            count,
+            image_offset,
+        })
+    }
+
+    fn entry(&self, index: usize) -> (u64, u64, usize, usize) {
+        let at = index * ENTRY_SIZE;
+        let e = self.entries;
+
+        (
+            read_u64(e, at).unwrap(),
+            read_u64(e, at + 8).unwrap(),
+            read_u32(e, at + 16).unwrap() as usize,
+            read_u32(e, at + 20).unwrap() as usize,
+        )
+    }
+
+    fn lookup(&self, addr: u64) -> Option<Symbol> {
+        let addr = addr.checked_sub(self.image_offset)?;
+
+        // find the last symbol starting at or below `addr`
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).0 <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let (start, size, name_off, name_len) = self.entry(lo.checked_sub(1)?);
+        let offset = addr - start;
+        // symbols without a size are accepted, as the linker does not always provide one
+        if size != 0 && offset >= size {
+            return None;
+        }
+
+        let name = self.names.get(name_off..name_off + name_len)?;
+
+        Some(Symbol {
+            name: core::str::from_utf8(name).ok()?,
+            offset,
+        })
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
@@ -0,0 +1,116 @@
+use super::{Clock, Instant, TICK};
+use crate::apic::{LocalApic, LVT_MASKED, LVT_TIMER, TIMER_CURRENT, TIMER_DIVIDE, TIMER_INITIAL};
+use core::arch::x86_64::__cpuid;
+use core::hint::spin_loop;
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const LVT_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// How long the timer gets measured against the TSC.
+const CALIBRATION: Duration = Duration::from_millis(10);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Fires every `TICK`.
+    Periodic,
+    /// Counts down to the next deadline.
+    OneShot,
+    /// Fires when the TSC reaches the next deadline.
+    TscDeadline,
+}
+
+/// The timer of the local APIC.
+pub struct ApicTimer {
+    local: &'static LocalApic,
+    mode: TimerMode,
+    /// Counter ticks per second, after the divider.
+    hz: u64,
+}
+
+impl ApicTimer {
+    /// Measures the timer against the TSC and starts it on `vector`.
+    ///
+    /// Only periodic timers fire right away, the others wait for `arm`.
+    pub fn new(local: &'static LocalApic, clock: &Clock, vector: u8, mode: TimerMode) -> Self {
+        let vector = vector as u32;
+
+        // SAFETY: the timer is masked while it gets measured
+        let remaining = unsafe {
+            local.write(LVT_TIMER, LVT_MASKED | vector);
+            local.write(TIMER_DIVIDE, DIVIDE_BY_16);
+            local.write(TIMER_INITIAL, u32::MAX);
+
+            let end = clock.now() + CALIBRATION;
+            while clock.now() < end {
+                spin_loop();
+            }
+
+            let remaining = local.read(TIMER_CURRENT);
+            local.write(TIMER_INITIAL, 0);
+            remaining
+        };
+        let hz = (u32::MAX - remaining) as u64 * 1000 / CALIBRATION.as_millis() as u64;
+
+        // SAFETY: the timer raises `vector`, which has a handler
+        unsafe {
+            match mode {
+                TimerMode::Periodic => {
+                    local.write(LVT_TIMER, LVT_PERIODIC | vector);
+                    local.write(TIMER_INITIAL, ticks(hz, TICK).max(1) as u32);
+                }
+                TimerMode::OneShot => local.write(LVT_TIMER, LVT_ONE_SHOT | vector),
+                TimerMode::TscDeadline => local.write(LVT_TIMER, LVT_TSC_DEADLINE | vector),
+            }
+        }
+
+        Self { local, mode, hz }
+    }
+
+    pub fn mode(&self) -> TimerMode {
+        self.mode
+    }
+
+    /// Counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        self.hz
+    }
+
+    /// Makes the timer fire at `deadline`, or stops it for `None`.
+    ///
+    /// Periodic timers keep on ticking.
+    pub fn arm(&self, clock: &Clock, deadline: Option<Instant>) {
+        without_interrupts(|| match self.mode {
+            TimerMode::Periodic => (),
+            TimerMode::OneShot => {
+                let count = deadline.map_or(0, |deadline| {
+                    let left = deadline.saturating_duration_since(clock.now());
+                    ticks(self.hz, left).clamp(1, u32::MAX as u64)
+                });
+                // SAFETY: writing the initial count restarts the timer, 0 stops it
+                unsafe { self.local.write(TIMER_INITIAL, count as u32) };
+            }
+            TimerMode::TscDeadline => {
+                // deadlines in the past fire right away, 0 disarms the timer
+                let tsc = deadline.map_or(0, |deadline| clock.tsc(deadline).max(1));
+                // SAFETY: the MSR exists in TSC-deadline mode
+                unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+            }
+        });
+    }
+}
+
+/// Whether the local APIC timer supports TSC-deadline mode.
+pub fn has_tsc_deadline() -> bool {
+    __cpuid(1).ecx & (1 << 24) != 0
+}
+
+fn ticks(hz: u64, duration: Duration) -> u64 {
+    (duration.as_nanos() * hz as u128 / 1_000_000_000).min(u64::MAX as u128) as u64
+}
@@ -0,0 +1,112 @@
+use super::rdtsc;
+use crate::acpi::HpetInfo;
+use crate::mem::{CacheMode, MemoryManager};
+use core::hint::spin_loop;
+use log::warn;
+use x86_64::VirtAddr;
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIG: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xF0;
+/// Size of the register block.
+const SIZE: u64 = 0x400;
+
+const COUNTER_64BIT: u64 = 1 << 13;
+const ENABLE: u64 = 1 << 0;
+
+/// Longest counter period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// The main counter of the high precision event timer.
+pub struct Hpet {
+    mmio: VirtAddr,
+    /// Femtoseconds per counter tick.
+    period_fs: u64,
+    /// Bits the counter actually has.
+    mask: u64,
+}
+
+impl Hpet {
+    /// Maps the HPET and starts its main counter.
+    ///
+    /// # Safety
+    /// `info` has to describe an HPET present in the system.
+    pub unsafe fn new(mem: &MemoryManager, info: &HpetInfo) -> Option<Self> {
+        // SAFETY: guaranteed by the caller, the registers are device memory
+        let mmio = match unsafe { mem.map_mmio(info.address, SIZE, CacheMode::Uncacheable) } {
+            Ok(mmio) => mmio,
+            Err(err) => {
+                warn!("Failed to map the HPET: {err}");
+                return None;
+            }
+        };
+
+        let mut this = Self {
+            mmio,
+            period_fs: 0,
+            mask: u64::MAX,
+        };
+        let capabilities = this.read(CAPABILITIES);
+        this.period_fs = capabilities >> 32;
+        if this.period_fs == 0 || this.period_fs > MAX_PERIOD_FS {
+            warn!(
+                "HPET {} has an invalid period of {} fs",
+                info.number, this.period_fs
+            );
+            // SAFETY: nothing else got to see the mapping
+            unsafe { mem.unmap_mmio(mmio, SIZE) };
+            return None;
+        }
+        if capabilities & COUNTER_64BIT == 0 {
+            this.mask = u32::MAX as u64;
+        }
+
+        let config = this.read(CONFIG);
+        // SAFETY: starting the main counter doesn't raise any interrupts
+        unsafe { this.write(CONFIG, config | ENABLE) };
+
+        Some(this)
+    }
+
+    /// Counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        FS_PER_SEC / self.period_fs
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER) & self.mask
+    }
+
+    /// Measures the frequency of the TSC against the main counter, in Hz.
+    ///
+    /// Busy waits for `millis`. Interrupts should be disabled, so the
+    /// measurement doesn't get stretched.
+    pub fn calibrate_tsc(&self, millis: u64) -> u64 {
+        let ticks = self.frequency() * millis / 1000;
+
+        let start = self.counter();
+        let start_tsc = rdtsc();
+        let mut end = start;
+        while end.wrapping_sub(start) & self.mask < ticks {
+            spin_loop();
+            end = self.counter();
+        }
+        let end_tsc = rdtsc();
+
+        let elapsed_fs = (end.wrapping_sub(start) & self.mask) as u128 * self.period_fs as u128;
+        ((end_tsc - start_tsc) as u128 * FS_PER_SEC as u128 / elapsed_fs) as u64
+    }
+
+    fn read(&self, reg: u64) -> u64 {
+        // SAFETY: the registers are mapped and reads have no side effects
+        unsafe { (self.mmio + reg).as_ptr::<u64>().read_volatile() }
+    }
+
+    /// # Safety
+    /// The configuration may not make the HPET raise interrupts nobody handles.
+    unsafe fn write(&mut self, reg: u64, value: u64) {
+        // SAFETY: the registers are mapped, the rest is up to the caller
+        unsafe { (self.mmio + reg).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+}
@@ -0,0 +1,366 @@
+mod apic_timer;
+mod date;
+mod hpet;
+mod pit;
+mod rtc;
+mod wheel;
+
+pub use apic_timer::TimerMode;
+pub use date::DateTime;
+pub use wheel::TimerId;
+
+use crate::acpi::HpetInfo;
+use crate::apic::{self, LocalApic};
+use crate::interrupts::{self, IrqReturn, Sharing};
+use crate::mem::MemoryManager;
+use alloc::boxed::Box;
+use apic_timer::{has_tsc_deadline, ApicTimer};
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::hint::spin_loop;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use hpet::Hpet;
+use log::{info, warn};
+use spinning_top::Spinlock;
+use wheel::TimerWheel;
+use x86_64::instructions::interrupts::{self as cpu_interrupts, without_interrupts};
+
+/// Period of the timer interrupt when it ticks periodically, and granularity of the timer wheel.
+const TICK: Duration = Duration::from_millis(1);
+/// How long each round of the TSC calibration takes.
+const CALIBRATION_MS: u64 = 10;
+const PIT_IRQ: u8 = 0;
+/// Rate of the periodic RTC interrupt.
+#[cfg(feature = "rtc-interrupt")]
+const RTC_HZ: u32 = 2;
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+static WALL_CLOCK: OnceCell<WallClock> = OnceCell::uninit();
+static TIMER: OnceCell<ApicTimer> = OnceCell::uninit();
+static WHEEL: Spinlock<TimerWheel> = Spinlock::new(TimerWheel::new());
+/// When the first timer in the wheel expires, in nanoseconds, `u64::MAX` without timers.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Set by the timer interrupt once `NEXT_DEADLINE` has passed.
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+/// Set once some timer raises interrupts.
+static TICKING: AtomicBool = AtomicBool::new(false);
+
+/// A point in time since the clock was set up, never going backwards.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+/// Converts between TSC values and instants.
+pub struct Clock {
+    /// TSC ticks per second.
+    tsc_hz: u64,
+    /// TSC value at `Instant::BOOT`.
+    tsc_start: u64,
+}
+
+/// The date read from the RTC, and when it was read.
+struct WallClock {
+    unix_nanos: u64,
+    read_at: Instant,
+}
+
+/// Calibrates the TSC, reads the RTC and starts the interrupt that drives the timers.
+///
+/// The local APIC timer is used if there is a local APIC, the PIT otherwise. Has to
+/// run after the interrupt controllers are set up.
+///
+/// # Safety
+/// `hpet` has to describe an HPET present in the system.
+pub unsafe fn init(mem: &MemoryManager, hpet: Option<&HpetInfo>) {
+    if !has_invariant_tsc() {
+        warn!("The TSC is not invariant, the clock may drift");
+    }
+
+    // SAFETY: guaranteed by the caller
+    let hpet = hpet.and_then(|info| unsafe { Hpet::new(mem, info) });
+    let source = if hpet.is_some() { "HPET" } else { "PIT" };
+
+    let tsc_hz = without_interrupts(|| {
+        let measure = || match &hpet {
+            Some(hpet) => hpet.calibrate_tsc(CALIBRATION_MS),
+            None => pit::calibrate_tsc(CALIBRATION_MS),
+        };
+        // getting held up can only make the TSC look faster
+        (0..3).map(|_| measure()).fold(u64::MAX, u64::min)
+    });
+    let clock = CLOCK.get_or_init(|| Clock {
+        tsc_hz,
+        tsc_start: rdtsc(),
+    });
+    info!(
+        "TSC at {}.{:03} MHz, calibrated against the {source}",
+        tsc_hz / 1_000_000,
+        tsc_hz / 1000 % 1000
+    );
+
+    let date = rtc::read();
+    WALL_CLOCK.init_once(|| WallClock {
+        unix_nanos: date.unix_nanos(),
+        read_at: Instant::now(),
+    });
+    info!("RTC time {date} UTC");
+
+    match apic::local_apic() {
+        Some(local) => start_apic_timer(local, clock),
+        None => start_pit(),
+    }
+
+    #[cfg(feature = "rtc-interrupt")]
+    start_rtc_interrupt();
+}
+
+/// The current date and time in UTC, once `init` has read the RTC.
+pub fn now() -> Option<DateTime> {
+    let wall = WALL_CLOCK.try_get().ok()?;
+    let elapsed = Instant::now().saturating_duration_since(wall.read_at);
+    Some(DateTime::from_unix_nanos(
+        wall.unix_nanos + elapsed.as_nanos() as u64,
+    ))
+}
+
+impl Instant {
+    /// When the clock was set up, everything before counts as this instant.
+    pub const BOOT: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Self {
+        CLOCK.try_get().map_or(Self::BOOT, Clock::now)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = self
+            .nanos
+            .checked_add(duration.as_nanos().try_into().ok()?)?;
+        Some(Instant { nanos })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        let ticks = rdtsc().saturating_sub(self.tsc_start);
+        let nanos = ticks as u128 * 1_000_000_000 / self.tsc_hz as u128;
+        Instant {
+            nanos: nanos as u64,
+        }
+    }
+
+    /// The TSC value at `instant`.
+    pub fn tsc(&self, instant: Instant) -> u64 {
+        let ticks = instant.nanos as u128 * self.tsc_hz as u128 / 1_000_000_000;
+        self.tsc_start
+            .saturating_add(ticks.min(u64::MAX as u128) as u64)
+    }
+}
+
+/// Calls `callback` once `deadline` has passed.
+///
+/// Timers run from `idle`, roughly in the order they expire. May not be called
+/// from interrupt handlers.
+pub fn add_timer(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let timer = WHEEL.lock().insert(deadline, Box::new(callback));
+    arm();
+    timer
+}
+
+/// Removes a timer before it runs, returns whether it was still pending.
+pub fn cancel_timer(timer: TimerId) -> bool {
+    let pending = WHEEL.lock().cancel(timer);
+    arm();
+    pending
+}
+
+/// Waits for at least `duration`, running the timers that expire meanwhile.
+///
+/// Needs interrupts enabled, busy waits if there is no timer interrupt.
+pub fn sleep(duration: Duration) {
+    assert!(
+        CLOCK.is_initialized(),
+        "sleeping before the clock is set up"
+    );
+    let deadline = Instant::now() + duration;
+
+    if !TICKING.load(Ordering::Acquire) {
+        while Instant::now() < deadline {
+            spin_loop();
+        }
+        return;
+    }
+
+    let wake = add_timer(deadline, || ());
+    while Instant::now() < deadline {
+        idle();
+    }
+    cancel_timer(wake);
+}
+
+/// Runs the timers that expired and halts until the next interrupt.
+pub fn idle() {
+    if EXPIRED.swap(false, Ordering::AcqRel) {
+        run_timers();
+    }
+
+    // the timer interrupt may not slip in between checking and halting
+    cpu_interrupts::disable();
+    if EXPIRED.load(Ordering::Acquire) {
+        cpu_interrupts::enable();
+    } else {
+        cpu_interrupts::enable_and_hlt();
+    }
+}
+
+/// Sleeps with a timer pending and another one cancelled, panics if they run
+/// when they shouldn't. Needs interrupts enabled.
+pub fn self_test() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    let start = Instant::now();
+    add_timer(start + TICK * 2, || FIRED.store(true, Ordering::Release));
+    let cancelled = add_timer(start + TICK * 3, || panic!("cancelled timer ran"));
+    assert!(cancel_timer(cancelled), "timer gone before it expired");
+
+    sleep(TICK * 10);
+    let slept = start.elapsed();
+    assert!(slept >= TICK * 10, "woke up after {slept:?}");
+    // without a timer interrupt sleeping busy waits and the timers never run
+    if TICKING.load(Ordering::Acquire) {
+        assert!(FIRED.load(Ordering::Acquire), "timer didn't run");
+    }
+
+    info!("Timer self-test passed, slept for {slept:?}");
+}
+
+pub fn idle_loop() -> ! {
+    loop {
+        idle();
+    }
+}
+
+fn start_apic_timer(local: &'static LocalApic, clock: &Clock) {
+    let mode = if cfg!(feature = "periodic-tick") {
+        TimerMode::Periodic
+    } else if has_tsc_deadline() {
+        TimerMode::TscDeadline
+    } else {
+        TimerMode::OneShot
+    };
+
+    let Some(vector) = interrupts::alloc_vector() else {
+        warn!("No free vector for the APIC timer, timers won't fire");
+        return;
+    };
+    interrupts::register(vector, "APIC timer", Sharing::Exclusive, timer_interrupt)
+        .expect("the timer vector is free");
+
+    let timer = TIMER.get_or_init(|| ApicTimer::new(local, clock, vector, mode));
+    TICKING.store(true, Ordering::Release);
+    info!(
+        "APIC timer in {:?} mode at {} kHz",
+        timer.mode(),
+        timer.frequency() / 1000
+    );
+}
+
+fn start_pit() {
+    match interrupts::register_isa_irq(PIT_IRQ, "PIT", Sharing::Exclusive, timer_interrupt) {
+        Ok(_) => {
+            pit::start_periodic(1_000_000_000 / TICK.as_nanos() as u64);
+            TICKING.store(true, Ordering::Release);
+            info!("No local APIC, ticking with the PIT");
+        }
+        Err(err) => warn!("Failed to set up the PIT interrupt: {err}, timers won't fire"),
+    }
+}
+
+#[cfg(feature = "rtc-interrupt")]
+fn start_rtc_interrupt() {
+    match interrupts::register_isa_irq(rtc::RTC_IRQ, "RTC", Sharing::Exclusive, rtc_interrupt) {
+        Ok(_) => info!(
+            "Periodic RTC interrupt at {} Hz",
+            rtc::enable_periodic(RTC_HZ)
+        ),
+        Err(err) => warn!("Failed to set up the RTC interrupt: {err}"),
+    }
+}
+
+#[cfg(feature = "rtc-interrupt")]
+fn rtc_interrupt() -> IrqReturn {
+    rtc::acknowledge_periodic();
+    IrqReturn::Handled
+}
+
+/// Programs the timer for the first timer in the wheel.
+fn arm() {
+    let next = WHEEL.lock().next_deadline();
+    NEXT_DEADLINE.store(next.map_or(u64::MAX, |next| next.nanos), Ordering::Release);
+
+    if let (Ok(timer), Ok(clock)) = (TIMER.try_get(), CLOCK.try_get()) {
+        timer.arm(clock, next);
+    }
+}
+
+fn run_timers() {
+    let now = Instant::now();
+    loop {
+        // the wheel may not be locked while a timer runs, it could add another one
+        let timer = WHEEL.lock().pop_expired(now);
+        let Some(timer) = timer else { break };
+        timer.run();
+    }
+    arm();
+}
+
+fn timer_interrupt() -> IrqReturn {
+    let next = NEXT_DEADLINE.load(Ordering::Acquire);
+    let deadline = (next != u64::MAX).then_some(Instant { nanos: next });
+
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        EXPIRED.store(true, Ordering::Release);
+    } else if let (Ok(timer), Ok(clock)) = (TIMER.try_get(), CLOCK.try_get()) {
+        // one-shot timers fire early if the deadline is too far away for their counter
+        timer.arm(clock, deadline);
+    }
+
+    IrqReturn::Handled
+}
+
+fn has_invariant_tsc() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: reading the time stamp counter has no side effects
+    unsafe { _rdtsc() }
+}
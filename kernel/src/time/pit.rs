@@ -0,0 +1,67 @@
+use super::rdtsc;
+use core::hint::spin_loop;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT's input clock.
+const PIT_HZ: u64 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller, which gates channel 2 and shows its output.
+const PORT_B: u16 = 0x61;
+const GATE2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUT2: u8 = 1 << 5;
+
+/// Channel 0, low then high byte, rate generator.
+const PERIODIC_CH0: u8 = 0b0011_0100;
+/// Channel 2, low then high byte, interrupt on terminal count.
+const ONE_SHOT_CH2: u8 = 0b1011_0000;
+
+/// Measures the frequency of the TSC against channel 2 of the PIT, in Hz.
+///
+/// Busy waits for `millis`, at most 54. Interrupts should be disabled, so the
+/// measurement doesn't get stretched.
+pub fn calibrate_tsc(millis: u64) -> u64 {
+    let count = PIT_HZ * millis / 1000;
+    assert!(count <= u16::MAX as u64, "the PIT can't wait {millis} ms");
+
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel = Port::<u8>::new(CHANNEL2);
+
+    // SAFETY: channel 2 only drives the PC speaker, which stays off
+    let (start, end) = unsafe {
+        let b = port_b.read();
+        port_b.write(b & !SPEAKER | GATE2);
+
+        command.write(ONE_SHOT_CH2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        while port_b.read() & OUT2 == 0 {
+            spin_loop();
+        }
+        let end = rdtsc();
+
+        port_b.write(b);
+        (start, end)
+    };
+
+    ((end - start) as u128 * PIT_HZ as u128 / count as u128) as u64
+}
+
+/// Raises IRQ0 `hz` times per second.
+pub fn start_periodic(hz: u64) {
+    let divisor = (PIT_HZ / hz).clamp(1, u16::MAX as u64);
+
+    // SAFETY: channel 0 only raises IRQ0
+    unsafe {
+        Port::<u8>::new(COMMAND).write(PERIODIC_CH0);
+        let mut channel = Port::<u8>::new(CHANNEL0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+}
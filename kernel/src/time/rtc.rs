@@ -0,0 +1,173 @@
+use super::DateTime;
+use core::hint::spin_loop;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+#[cfg(feature = "rtc-interrupt")]
+const STATUS_C: u8 = 0x0C;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+#[cfg(feature = "rtc-interrupt")]
+const RATE_MASK: u8 = 0x0F;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+#[cfg(feature = "rtc-interrupt")]
+const PERIODIC_ENABLE: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Frequency of the RTC's oscillator, the periodic interrupt divides it.
+#[cfg(feature = "rtc-interrupt")]
+const BASE_HZ: u32 = 32_768;
+/// The RTC only stores two digits of the year.
+const CENTURY: u16 = 2000;
+
+/// The ISA IRQ of the RTC.
+#[cfg(feature = "rtc-interrupt")]
+pub const RTC_IRQ: u8 = 8;
+
+static CMOS: Spinlock<Cmos> = Spinlock::new(Cmos {
+    index: Port::new(CMOS_INDEX),
+    data: Port::new(CMOS_DATA),
+});
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+/// The registers holding the date, as the RTC stores them.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+/// Reads the date and time from the RTC, which keeps UTC.
+///
+/// Takes until the RTC has finished updating, at most a few milliseconds.
+pub fn read() -> DateTime {
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+
+        // read until two reads in a row agree, so an update in between can't mix two dates
+        let mut regs = cmos.read_registers();
+        loop {
+            let again = cmos.read_registers();
+            if again == regs {
+                break;
+            }
+            regs = again;
+        }
+
+        let status = cmos.read(STATUS_B);
+        regs.decode(status)
+    })
+}
+
+/// Makes the RTC raise its IRQ at `hz`, rounded down to a power of two from 2 to 8192.
+///
+/// Every interrupt has to be acknowledged with `acknowledge_periodic`.
+#[cfg(feature = "rtc-interrupt")]
+pub fn enable_periodic(hz: u32) -> u32 {
+    // the frequency is `BASE_HZ >> (rate - 1)`
+    let shift = (BASE_HZ.ilog2() - hz.clamp(2, 8192).ilog2()) as u8;
+    let rate = shift + 1;
+
+    without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, a & !RATE_MASK | rate);
+        let b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, b | PERIODIC_ENABLE);
+        cmos.read(STATUS_C);
+    });
+
+    BASE_HZ >> shift
+}
+
+/// Acknowledges a periodic interrupt, the RTC raises no more until it is.
+#[cfg(feature = "rtc-interrupt")]
+pub fn acknowledge_periodic() {
+    CMOS.lock().read(STATUS_C);
+}
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        // SAFETY: selecting and reading a register has no side effects but for status C,
+        //         which acknowledges the interrupts. Bit 7 of the index stays clear, it
+        //         would mask NMIs.
+        unsafe {
+            self.index.write(reg);
+            self.data.read()
+        }
+    }
+
+    #[cfg(feature = "rtc-interrupt")]
+    fn write(&mut self, reg: u8, value: u8) {
+        // SAFETY: only the RTC's configuration changes
+        unsafe {
+            self.index.write(reg);
+            self.data.write(value);
+        }
+    }
+
+    fn read_registers(&mut self) -> Registers {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            spin_loop();
+        }
+
+        Registers {
+            second: self.read(SECONDS),
+            minute: self.read(MINUTES),
+            hour: self.read(HOURS),
+            day: self.read(DAY),
+            month: self.read(MONTH),
+            year: self.read(YEAR),
+        }
+    }
+}
+
+impl Registers {
+    /// Converts BCD and 12 hour times according to `status`, the value of status B.
+    fn decode(self, status: u8) -> DateTime {
+        let value = |raw: u8| match status & BINARY {
+            0 => (raw >> 4) * 10 + (raw & 0x0F),
+            _ => raw,
+        };
+
+        let mut hour = value(self.hour & !HOUR_PM);
+        if status & HOURS_24 == 0 {
+            // 12 AM is midnight, 12 PM noon
+            hour %= 12;
+            if self.hour & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        DateTime {
+            year: CENTURY + value(self.year) as u16,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+            nanos: 0,
+        }
+    }
+}
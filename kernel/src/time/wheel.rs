@@ -0,0 +1,110 @@
+use super::{Instant, TICK};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+const SLOTS: usize = 256;
+
+/// Identifies a timer, to cancel it again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    id: u64,
+    slot: usize,
+}
+
+/// A hashed timer wheel: timers are kept in the slot of the tick they expire in,
+/// modulo the number of slots, so timers further away share slots with closer ones.
+pub struct TimerWheel {
+    slots: [Vec<Timer>; SLOTS],
+    /// First tick with slots that may still hold expired timers.
+    current: u64,
+    next_id: u64,
+    len: usize,
+}
+
+pub struct Timer {
+    id: u64,
+    deadline: Instant,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+impl TimerWheel {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Vec::new() }; SLOTS],
+            current: 0,
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, deadline: Instant, callback: Box<dyn FnOnce() + Send>) -> TimerId {
+        // timers that are overdue already go into the slot that gets checked next
+        let slot = tick(deadline).max(self.current) as usize % SLOTS;
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.slots[slot].push(Timer {
+            id,
+            deadline,
+            callback,
+        });
+        self.len += 1;
+
+        TimerId { id, slot }
+    }
+
+    /// Removes a timer that has not expired yet.
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        let slot = &mut self.slots[timer.slot];
+        let Some(index) = slot.iter().position(|t| t.id == timer.id) else {
+            return false;
+        };
+
+        slot.swap_remove(index);
+        self.len -= 1;
+        true
+    }
+
+    /// Takes out one of the timers that expired at `now`.
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        let now_tick = tick(now);
+        // the last turn of the wheel visits every slot
+        self.current = self
+            .current
+            .max((now_tick + 1).saturating_sub(SLOTS as u64));
+
+        while self.current <= now_tick {
+            let slot = &mut self.slots[self.current as usize % SLOTS];
+            if let Some(index) = slot.iter().position(|t| t.deadline <= now) {
+                self.len -= 1;
+                return Some(slot.swap_remove(index));
+            }
+
+            // timers can still be added to the tick that is running
+            if self.current == now_tick {
+                break;
+            }
+            self.current += 1;
+        }
+
+        None
+    }
+
+    /// When the next timer expires.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+        self.slots.iter().flatten().map(|t| t.deadline).min()
+    }
+}
+
+impl Timer {
+    pub fn run(self) {
+        (self.callback)()
+    }
+}
+
+fn tick(instant: Instant) -> u64 {
+    instant.nanos / TICK.as_nanos() as u64
+}
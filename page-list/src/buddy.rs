@@ -0,0 +1,287 @@
+use crate::{FreeRange, PhysWindow, PAGE_SIZE};
+use core::ops::Range;
+use core::ptr::NonNull;
+
+/// Largest blocks hold 2^MAX_ORDER pages, enough for a 1GiB page.
+pub const MAX_ORDER: u32 = 18;
+const ORDERS: usize = MAX_ORDER as usize + 1;
+
+/// Links stored in the first page of every free block.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct RawLink {
+    next: Option<u64>,
+    prev: Option<u64>,
+}
+
+/// Buddy allocator for physical pages.
+///
+/// Free blocks of 2^order pages are kept in a doubly linked list per order,
+/// stored in the blocks themselves like the nodes of a [`FreeList`](crate::FreeList).
+/// On top of that it needs a map with a byte per page, which records the pages
+/// starting a free block together with its order, so buddies are found in O(1).
+pub struct BuddyAllocator<W> {
+    window: W,
+    heads: [Option<u64>; ORDERS],
+    /// `order + 1` for every page starting a free block, `0` everywhere else.
+    map: Option<NonNull<u8>>,
+    /// Page numbers this allocator may hand out.
+    pages: Range<u64>,
+    free: usize,
+}
+
+// SAFETY: the map is only accessed through `&mut self`
+unsafe impl<W: Send> Send for BuddyAllocator<W> {}
+
+impl<W> BuddyAllocator<W> {
+    pub const fn new(window: W) -> Self {
+        Self {
+            window,
+            heads: [None; ORDERS],
+            map: None,
+            pages: 0..0,
+            free: 0,
+        }
+    }
+
+    pub fn window(&self) -> &W {
+        &self.window
+    }
+
+    /// Number of free pages.
+    pub fn free_pages(&self) -> usize {
+        self.free
+    }
+
+    /// Bytes of map needed for physical memory up to `end`.
+    pub const fn map_size(end: u64) -> usize {
+        end.div_ceil(PAGE_SIZE) as usize
+    }
+
+    /// Lets the allocator manage the pages in the physical range `range`, using `map`.
+    ///
+    /// # Safety
+    /// `map` has to be zeroed and valid for reads and writes of `map_size(range.end)`
+    /// bytes for as long as the allocator lives. Other allocators may share it,
+    /// as long as their ranges don't overlap. Has to be called before the first `free`.
+    pub unsafe fn set_map(&mut self, map: NonNull<u8>, range: Range<u64>) {
+        self.map = Some(map);
+        self.pages = range.start.div_ceil(PAGE_SIZE)..range.end / PAGE_SIZE;
+    }
+}
+
+impl<W: PhysWindow> BuddyAllocator<W> {
+    /// Free blocks, ordered by size and then by their position in the lists.
+    pub fn blocks(&self) -> impl Iterator<Item = FreeRange> + '_ {
+        (0..ORDERS).flat_map(move |order| {
+            let mut next = self.heads[order];
+
+            core::iter::from_fn(move || {
+                let page = next?;
+                next = self.read(page).next;
+
+                Some(FreeRange {
+                    start: page * PAGE_SIZE,
+                    count: 1 << order,
+                })
+            })
+        })
+    }
+
+    /// Allocates `count` contiguous pages starting at a multiple of `align` bytes.
+    ///
+    /// The block is rounded up to a power of two and the unused rest freed again.
+    ///
+    /// # Safety
+    /// The allocator has to be used exclusively.
+    pub unsafe fn alloc(&mut self, count: usize, align: u64) -> Option<u64> {
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+        if count == 0 {
+            return None;
+        }
+
+        let order = count
+            .next_power_of_two()
+            .trailing_zeros()
+            .max((align / PAGE_SIZE).trailing_zeros());
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let found = (order..=MAX_ORDER).find(|&o| self.heads[o as usize].is_some())?;
+        let page = self.pop(found);
+
+        // split down to the requested order, keeping the lower half every time
+        for o in (order..found).rev() {
+            self.push(page + (1 << o), o);
+        }
+        self.free -= 1 << order;
+
+        let size = 1usize << order;
+        if count < size {
+            // SAFETY: the rest of the block was just taken from the allocator
+            unsafe { self.free((page + count as u64) * PAGE_SIZE, size - count) };
+        }
+
+        Some(page * PAGE_SIZE)
+    }
+
+    /// Takes up to `max` pages from the largest free block.
+    ///
+    /// # Safety
+    /// The allocator has to be used exclusively.
+    pub unsafe fn alloc_largest(&mut self, max: usize) -> Option<FreeRange> {
+        if max == 0 {
+            return None;
+        }
+
+        let order = (0..=MAX_ORDER)
+            .rev()
+            .find(|&o| self.heads[o as usize].is_some())?;
+        let page = self.pop(order);
+
+        let size = 1usize << order;
+        self.free -= size;
+
+        let count = size.min(max);
+        if count < size {
+            // SAFETY: the rest of the block was just taken from the allocator
+            unsafe { self.free((page + count as u64) * PAGE_SIZE, size - count) };
+        }
+
+        Some(FreeRange {
+            start: page * PAGE_SIZE,
+            count,
+        })
+    }
+
+    /// Gives back `count` pages starting at `start`, merging them with their buddies.
+    ///
+    /// # Safety
+    /// The pages have to be within the range given to `set_map`, unused and
+    /// exclusively owned by the caller. The allocator has to be used exclusively.
+    pub unsafe fn free(&mut self, start: u64, count: usize) {
+        assert_eq!(start % PAGE_SIZE, 0, "unaligned pages at {start:#x}");
+
+        let mut page = start / PAGE_SIZE;
+        let end = page + count as u64;
+        assert!(
+            self.pages.start <= page && end <= self.pages.end,
+            "pages at {start:#x} outside of the allocator"
+        );
+
+        while page < end {
+            let fits = (end - page).ilog2();
+            let order = page.trailing_zeros().min(fits).min(MAX_ORDER);
+
+            self.free_block(page, order);
+            page += 1 << order;
+        }
+
+        self.free += count;
+    }
+
+    fn free_block(&mut self, mut page: u64, mut order: u32) {
+        for o in order..=MAX_ORDER {
+            let head = page & !((1 << o) - 1);
+            assert_ne!(
+                self.entry(head),
+                o as u8 + 1,
+                "double free of pages at {:#x}",
+                page * PAGE_SIZE
+            );
+        }
+
+        while order < MAX_ORDER {
+            let buddy = page ^ (1 << order);
+            let inside = self.pages.start <= buddy && buddy + (1 << order) <= self.pages.end;
+
+            if !inside || self.entry(buddy) != order as u8 + 1 {
+                break;
+            }
+
+            self.unlink(buddy, order);
+            page = page.min(buddy);
+            order += 1;
+        }
+
+        self.push(page, order);
+    }
+
+    fn push(&mut self, page: u64, order: u32) {
+        let next = self.heads[order as usize];
+        if let Some(next) = next {
+            self.write(
+                next,
+                RawLink {
+                    prev: Some(page),
+                    ..self.read(next)
+                },
+            );
+        }
+
+        self.write(page, RawLink { next, prev: None });
+        self.heads[order as usize] = Some(page);
+        self.set_entry(page, order as u8 + 1);
+    }
+
+    fn pop(&mut self, order: u32) -> u64 {
+        let page = self.heads[order as usize].expect("popped an empty order");
+        self.unlink(page, order);
+        page
+    }
+
+    fn unlink(&mut self, page: u64, order: u32) {
+        let RawLink { next, prev } = self.read(page);
+
+        match prev {
+            Some(prev) => self.write(
+                prev,
+                RawLink {
+                    next,
+                    ..self.read(prev)
+                },
+            ),
+            None => self.heads[order as usize] = next,
+        }
+        if let Some(next) = next {
+            self.write(
+                next,
+                RawLink {
+                    prev,
+                    ..self.read(next)
+                },
+            );
+        }
+
+        self.set_entry(page, 0);
+    }
+
+    fn entry(&self, page: u64) -> u8 {
+        // SAFETY: `set_map` guarantees the map covers every page of the allocator
+        unsafe { self.map_entry(page).read() }
+    }
+
+    fn set_entry(&mut self, page: u64, value: u8) {
+        // SAFETY: see `entry`
+        unsafe { self.map_entry(page).write(value) }
+    }
+
+    fn map_entry(&self, page: u64) -> *mut u8 {
+        let map = self.map.expect("buddy allocator used without a map");
+        // SAFETY: see `entry`, the callers only pass pages within `self.pages`
+        unsafe { map.as_ptr().add(page as usize) }
+    }
+
+    fn read(&self, page: u64) -> RawLink {
+        let ptr = self.window.page(page * PAGE_SIZE).cast::<RawLink>();
+        // SAFETY: the page starts a free block, nobody else uses it
+        unsafe { ptr.as_ptr().read_volatile() }
+    }
+
+    fn write(&mut self, page: u64, link: RawLink) {
+        let ptr = self.window.page(page * PAGE_SIZE).cast::<RawLink>();
+        // SAFETY: the page starts a free block, nobody else uses it
+        unsafe { ptr.as_ptr().write_volatile(link) }
+    }
+}
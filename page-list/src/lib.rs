@@ -0,0 +1,33 @@
+//! Free list and buddy allocator for physical pages, the backends of the kernel's
+//! frame allocator.
+//!
+//! Both keep their bookkeeping inside the free pages themselves and only
+//! reach them through a [`PhysWindow`], which lets the same code run on top
+//! of the kernel's physical memory mapping or on plain memory in host tests.
+#![no_std]
+#![deny(unsafe_op_in_unsafe_fn)]
+
+mod buddy;
+mod list;
+mod regions;
+
+#[cfg(test)]
+mod tests;
+
+pub use buddy::{BuddyAllocator, MAX_ORDER};
+pub use list::{FreeList, FreeRange};
+pub use regions::{whole_pages, CombinedRegions};
+
+use core::ptr::NonNull;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Access to physical memory.
+///
+/// # Safety
+/// For every page aligned address handed to an allocator, `page` has to
+/// return a pointer aligned to [`PAGE_SIZE`] that is valid for reads and writes
+/// of a whole page. Different pages must never overlap.
+pub unsafe trait PhysWindow {
+    fn page(&self, addr: u64) -> NonNull<u8>;
+}
@@ -0,0 +1,268 @@
+use crate::{PhysWindow, PAGE_SIZE};
+use core::iter;
+
+/// Header stored in the first page of every free range.
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct RawNode {
+    next: Option<u64>,
+    prev: Option<u64>,
+    count: usize,
+}
+
+/// A free range together with its header.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Node {
+    pub(crate) addr: u64,
+    pub(crate) next: Option<u64>,
+    pub(crate) prev: Option<u64>,
+    pub(crate) count: usize,
+}
+
+/// Range of free pages starting at physical address `start`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FreeRange {
+    pub start: u64,
+    pub count: usize,
+}
+
+/// Free page ranges kept as a doubly linked list sorted by address.
+///
+/// Every range stores its node in its own first page, so the list needs no
+/// memory besides the pages it manages. Adjacent ranges are always merged.
+pub struct FreeList<W> {
+    window: W,
+    head: Option<u64>,
+    free: usize,
+}
+
+impl Node {
+    fn end(&self) -> u64 {
+        self.addr + self.count as u64 * PAGE_SIZE
+    }
+}
+
+impl FreeRange {
+    pub fn end(&self) -> u64 {
+        self.start + self.count as u64 * PAGE_SIZE
+    }
+}
+
+impl<W> FreeList<W> {
+    pub const fn new(window: W) -> Self {
+        Self {
+            window,
+            head: None,
+            free: 0,
+        }
+    }
+
+    pub fn window(&self) -> &W {
+        &self.window
+    }
+
+    /// Number of free pages.
+    pub fn free_pages(&self) -> usize {
+        self.free
+    }
+}
+
+impl<W: PhysWindow> FreeList<W> {
+    /// Free ranges in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = FreeRange> + '_ {
+        self.nodes().map(|node| FreeRange {
+            start: node.addr,
+            count: node.count,
+        })
+    }
+
+    /// Largest free range, the first one if there are several.
+    pub fn largest(&self) -> Option<FreeRange> {
+        self.iter().reduce(|best, range| {
+            if range.count > best.count {
+                range
+            } else {
+                best
+            }
+        })
+    }
+
+    pub(crate) fn nodes(&self) -> impl Iterator<Item = Node> + '_ {
+        iter::successors(self.head.map(|head| self.read(head)), |node| {
+            node.next.map(|next| self.read(next))
+        })
+    }
+
+    /// Takes `count` pages starting at an address aligned to `align` bytes (first fit)
+    /// and returns the physical address of the first one.
+    ///
+    /// # Safety
+    /// The pages in the list must not have been touched since they were freed.
+    pub unsafe fn alloc(&mut self, count: usize, align: u64) -> Option<u64> {
+        assert!(count > 0);
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+
+        let size = (count as u64).checked_mul(PAGE_SIZE)?;
+
+        let node = self.nodes().find(|node| {
+            let aligned = node.addr.checked_next_multiple_of(align);
+            aligned
+                .and_then(|aligned| aligned.checked_add(size))
+                .is_some_and(|end| end <= node.end())
+        })?;
+
+        let start = node.addr.next_multiple_of(align);
+        let head_pages = ((start - node.addr) / PAGE_SIZE) as usize;
+        let tail_pages = ((node.end() - (start + size)) / PAGE_SIZE) as usize;
+
+        // the last node in front of the allocation
+        let mut left = node.prev;
+        if head_pages > 0 {
+            self.write(Node {
+                count: head_pages,
+                ..node
+            });
+            left = Some(node.addr);
+        }
+
+        if tail_pages > 0 {
+            let tail = start + size;
+            self.write(Node {
+                addr: tail,
+                next: node.next,
+                prev: left,
+                count: tail_pages,
+            });
+            self.link(left, Some(tail));
+            self.link(Some(tail), node.next);
+        } else {
+            self.link(left, node.next);
+        }
+
+        self.free -= count;
+        Some(start)
+    }
+
+    /// Takes up to `max` pages from the start of the largest free range.
+    ///
+    /// # Safety
+    /// The pages in the list must not have been touched since they were freed.
+    pub unsafe fn alloc_largest(&mut self, max: usize) -> Option<FreeRange> {
+        assert!(max > 0);
+
+        let largest = self.largest()?;
+        let count = largest.count.min(max);
+
+        // the largest range starts with a fitting page aligned block, so first fit ends up there
+        // unless an earlier range is big enough as well, which is just as good
+        let start = unsafe { self.alloc(count, PAGE_SIZE) }?;
+        Some(FreeRange { start, count })
+    }
+
+    /// Returns `count` pages starting at `start` to the list, merging them with their neighbours.
+    ///
+    /// # Safety
+    /// - The pages must be unused and reachable through the window
+    /// - The pages in the list must not have been touched since they were freed
+    pub unsafe fn free(&mut self, start: u64, count: usize) {
+        assert!(count > 0);
+        assert_eq!(start % PAGE_SIZE, 0, "unaligned page address {:#x}", start);
+
+        let end = start + count as u64 * PAGE_SIZE;
+
+        // find the nodes surrounding the range
+        let mut prev: Option<Node> = None;
+        let mut next = self.head.map(|head| self.read(head));
+        while let Some(node) = next {
+            if node.addr > start {
+                break;
+            }
+            prev = Some(node);
+            next = node.next.map(|next| self.read(next));
+        }
+
+        if let Some(prev) = prev {
+            assert!(prev.end() <= start, "double free of pages at {:#x}", start);
+        }
+        if let Some(next) = next {
+            assert!(end <= next.addr, "double free of pages at {:#x}", start);
+        }
+
+        self.free += count;
+
+        let mut node = match prev {
+            Some(prev) if prev.end() == start => Node {
+                count: prev.count + count,
+                ..prev
+            },
+            _ => {
+                let node = Node {
+                    addr: start,
+                    next: next.map(|n| n.addr),
+                    prev: prev.map(|p| p.addr),
+                    count,
+                };
+                self.write(node);
+                self.link(node.prev, Some(start));
+                self.link(Some(start), node.next);
+                node
+            }
+        };
+
+        if let Some(next) = next.filter(|next| next.addr == end) {
+            node.count += next.count;
+            node.next = next.next;
+            self.link(Some(node.addr), next.next);
+        }
+
+        self.write(node);
+    }
+
+    /// Points `a` to `b` and `b` back to `a`, `None` for `a` means `b` becomes the head.
+    fn link(&mut self, a: Option<u64>, b: Option<u64>) {
+        match a {
+            Some(a) => self.write(Node {
+                next: b,
+                ..self.read(a)
+            }),
+            None => self.head = b,
+        }
+
+        if let Some(b) = b {
+            self.write(Node {
+                prev: a,
+                ..self.read(b)
+            });
+        }
+    }
+
+    fn read(&self, addr: u64) -> Node {
+        // SAFETY: only addresses of free pages end up in the list, the window makes them accessible
+        let raw = unsafe { self.window.page(addr).cast::<RawNode>().as_ptr().read() };
+
+        Node {
+            addr,
+            next: raw.next,
+            prev: raw.prev,
+            count: raw.count,
+        }
+    }
+
+    fn write(&self, node: Node) {
+        let raw = RawNode {
+            next: node.next,
+            prev: node.prev,
+            count: node.count,
+        };
+
+        // volatile, as the compiler never sees anyone reading this memory through the window
+        // SAFETY: only addresses of free pages end up in the list, the window makes them accessible
+        unsafe {
+            self.window
+                .page(node.addr)
+                .cast::<RawNode>()
+                .as_ptr()
+                .write_volatile(raw)
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::PAGE_SIZE;
+use core::ops::Range;
+
+/// Merges directly adjacent ranges of a memory map.
+///
+/// Firmware tends to split usable memory into many small regions that only
+/// touch each other, merging them first means fewer pages get lost to trimming.
+pub struct CombinedRegions<I> {
+    inner: I,
+    current: Option<Range<u64>>,
+}
+
+impl<I> CombinedRegions<I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            current: None,
+        }
+    }
+}
+
+impl<I> Iterator for CombinedRegions<I>
+where
+    I: Iterator<Item = Range<u64>>,
+{
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut current = match self.current.take() {
+            Some(current) => current,
+            None => self.inner.next()?,
+        };
+
+        for next in self.inner.by_ref() {
+            if current.end != next.start {
+                // the region we got from the iterator has to be looked at again
+                self.current = Some(next);
+                return Some(current);
+            }
+
+            current.end = next.end;
+        }
+
+        Some(current)
+    }
+}
+
+/// Shrinks `range` to the pages fully contained in it, `None` if there are none.
+pub fn whole_pages(range: Range<u64>) -> Option<Range<u64>> {
+    let start = range.start.checked_next_multiple_of(PAGE_SIZE)?;
+    let end = range.end - range.end % PAGE_SIZE;
+
+    (start < end).then_some(start..end)
+}
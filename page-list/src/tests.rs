@@ -0,0 +1,577 @@
+extern crate std;
+
+use crate::{
+    whole_pages, BuddyAllocator, CombinedRegions, FreeList, FreeRange, PhysWindow, MAX_ORDER,
+    PAGE_SIZE,
+};
+use core::ops::Range;
+use core::ptr::NonNull;
+use proptest::prelude::*;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::vec::Vec;
+
+/// Fake physical memory of `len` pages, starting at physical address `base`.
+struct FakeRam {
+    base: u64,
+    len: usize,
+    pages: NonNull<u8>,
+}
+
+impl FakeRam {
+    fn new(base: u64, len: usize) -> Self {
+        let pages = unsafe { alloc_zeroed(Self::layout(len)) };
+
+        Self {
+            base,
+            len,
+            pages: NonNull::new(pages).expect("out of host memory"),
+        }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1) * PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap()
+    }
+
+    fn index(&self, addr: u64) -> usize {
+        assert_eq!(addr % PAGE_SIZE, 0, "unaligned page {addr:#x}");
+        assert!(addr >= self.base, "page {addr:#x} below fake ram");
+
+        let index = ((addr - self.base) / PAGE_SIZE) as usize;
+        assert!(index < self.len, "page {addr:#x} above fake ram");
+        index
+    }
+
+    fn addr(&self, index: usize) -> u64 {
+        self.base + index as u64 * PAGE_SIZE
+    }
+
+    /// Fills allocated pages with `tag`, so the list touching them can be detected.
+    fn fill(&self, start: u64, count: usize, tag: u8) {
+        for i in 0..count {
+            let page = self.page(start + i as u64 * PAGE_SIZE);
+            unsafe { page.as_ptr().write_bytes(tag, PAGE_SIZE as usize) };
+        }
+    }
+
+    fn check_filled(&self, start: u64, count: usize, tag: u8) {
+        for i in 0..count {
+            let page = self.page(start + i as u64 * PAGE_SIZE);
+            let bytes = unsafe { core::slice::from_raw_parts(page.as_ptr(), PAGE_SIZE as usize) };
+            assert!(
+                bytes.iter().all(|&b| b == tag),
+                "allocated page {:#x} was modified",
+                start + i as u64 * PAGE_SIZE
+            );
+        }
+    }
+}
+
+unsafe impl PhysWindow for &FakeRam {
+    fn page(&self, addr: u64) -> NonNull<u8> {
+        let index = self.index(addr);
+        unsafe { self.pages.add(index * PAGE_SIZE as usize) }
+    }
+}
+
+impl Drop for FakeRam {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.pages.as_ptr(), Self::layout(self.len)) };
+    }
+}
+
+/// Checks the list's invariants and that it holds exactly the pages marked free in `model`.
+fn check(list: &FreeList<&FakeRam>, model: &[bool]) {
+    let ram = *list.window();
+
+    let mut prev: Option<crate::list::Node> = None;
+    for node in list.nodes() {
+        assert!(node.count > 0, "empty node at {:#x}", node.addr);
+        assert_eq!(node.prev, prev.map(|p| p.addr), "broken prev link");
+
+        if let Some(prev) = prev {
+            let prev_end = prev.addr + prev.count as u64 * PAGE_SIZE;
+            assert!(prev_end < node.addr, "unsorted or unmerged nodes");
+        }
+
+        prev = Some(node);
+    }
+
+    let mut listed = std::vec![false; model.len()];
+    for range in list.iter() {
+        let first = ram.index(range.start);
+        for page in &mut listed[first..first + range.count] {
+            *page = true;
+        }
+    }
+
+    assert_eq!(listed, model, "list differs from model");
+    assert_eq!(list.free_pages(), model.iter().filter(|&&f| f).count());
+}
+
+/// Maximal runs of free pages, which is what a fully merged list has to look like.
+fn runs(ram: &FakeRam, model: &[bool]) -> Vec<FreeRange> {
+    let mut runs: Vec<FreeRange> = Vec::new();
+
+    for (i, _) in model.iter().enumerate().filter(|(_, &f)| f) {
+        match runs.last_mut() {
+            Some(run) if run.end() == ram.addr(i) => run.count += 1,
+            _ => runs.push(FreeRange {
+                start: ram.addr(i),
+                count: 1,
+            }),
+        }
+    }
+
+    runs
+}
+
+/// First fit as the list does it: the lowest aligned run of `count` free pages.
+fn model_alloc(ram: &FakeRam, model: &[bool], count: usize, align: u64) -> Option<usize> {
+    (0..model.len())
+        .filter(|&i| ram.addr(i).is_multiple_of(align))
+        .find(|&i| i + count <= model.len() && model[i..i + count].iter().all(|&f| f))
+}
+
+/// Frees the pages marked in `initial` one by one, in the given order.
+fn free_pages(list: &mut FreeList<&FakeRam>, initial: &[bool], order: &[usize]) {
+    let ram = *list.window();
+
+    for &i in order.iter().filter(|&&i| initial[i]) {
+        unsafe { list.free(ram.addr(i), 1) };
+    }
+}
+
+/// Buddy allocator over `ram`, with its map owned by the caller.
+fn buddy<'a>(ram: &'a FakeRam, map: &mut Vec<u8>) -> BuddyAllocator<&'a FakeRam> {
+    let end = ram.addr(ram.len);
+    *map = std::vec![0; BuddyAllocator::<&FakeRam>::map_size(end)];
+
+    let mut buddy = BuddyAllocator::new(ram);
+    unsafe { buddy.set_map(NonNull::new(map.as_mut_ptr()).unwrap(), ram.base..end) };
+    buddy
+}
+
+/// Checks that the free blocks are exactly the maximal aligned blocks of pages marked free in `model`.
+fn check_buddy(buddy: &BuddyAllocator<&FakeRam>, model: &[bool]) {
+    let ram = *buddy.window();
+
+    let mut expected = Vec::new();
+    let mut i = 0;
+    while i < model.len() {
+        if !model[i] {
+            i += 1;
+            continue;
+        }
+
+        let page = ram.addr(i) / PAGE_SIZE;
+        let order = (0..=MAX_ORDER)
+            .rev()
+            .find(|&o| {
+                let size = 1usize << o;
+                page.is_multiple_of(size as u64)
+                    && i + size <= model.len()
+                    && model[i..i + size].iter().all(|&f| f)
+                    && page >= ram.base / PAGE_SIZE
+            })
+            .unwrap();
+
+        expected.push(FreeRange {
+            start: ram.addr(i),
+            count: 1 << order,
+        });
+        i += 1 << order;
+    }
+
+    let mut blocks: Vec<_> = buddy.blocks().collect();
+    blocks.sort_by_key(|block| block.start);
+
+    assert_eq!(blocks, expected, "blocks are not fully merged");
+    assert_eq!(buddy.free_pages(), model.iter().filter(|&&f| f).count());
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+    Alloc { count: usize, align_shift: u32 },
+    Free(usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (1..24usize, 0..5u32).prop_map(|(count, align_shift)| Op::Alloc { count, align_shift }),
+        any::<usize>().prop_map(Op::Free),
+    ]
+}
+
+/// Free map of `len` pages and an order to free them in.
+fn free_map(len: usize) -> impl Strategy<Value = (Vec<bool>, Vec<usize>)> {
+    (
+        proptest::collection::vec(any::<bool>(), len),
+        Just((0..len).collect::<Vec<_>>()).prop_shuffle(),
+    )
+}
+
+/// Memory map in the shape the bootloader hands over: contiguous regions of
+/// mixed kinds with unaligned boundaries, usable memory split into adjacent
+/// pieces and the occasional hole.
+fn boot_map() -> impl Strategy<Value = Vec<(Range<u64>, bool)>> {
+    let region = (
+        0..16u64,
+        prop_oneof![3 => Just(0u64), 1 => 1..PAGE_SIZE],
+        any::<bool>(),
+        prop::bool::weighted(0.1),
+    );
+
+    proptest::collection::vec(region, 1..48).prop_map(|regions| {
+        let mut cursor = 0;
+        let mut map = Vec::new();
+
+        for (pages, extra, usable, hole) in regions {
+            let len = pages * PAGE_SIZE + extra;
+            if len == 0 {
+                continue;
+            }
+
+            if !hole {
+                map.push((cursor..cursor + len, usable));
+            }
+            cursor += len;
+        }
+
+        map
+    })
+}
+
+proptest! {
+    #[test]
+    fn matches_model((initial, order) in free_map(256), ops in proptest::collection::vec(op(), 1..200)) {
+        let ram = FakeRam::new(0x10_0000, initial.len());
+        let mut list = FreeList::new(&ram);
+
+        free_pages(&mut list, &initial, &order);
+        check(&list, &initial);
+
+        let mut model = initial.clone();
+
+        let mut allocations: Vec<(u64, usize, u8)> = Vec::new();
+
+        for (tag, op) in ops.into_iter().enumerate() {
+            let tag = (tag % 255) as u8 + 1;
+
+            match op {
+                Op::Alloc { count, align_shift } => {
+                    let align = PAGE_SIZE << align_shift;
+                    let expected = model_alloc(&ram, &model, count, align);
+                    let got = unsafe { list.alloc(count, align) };
+
+                    prop_assert_eq!(got, expected.map(|i| ram.addr(i)));
+
+                    if let Some(start) = got {
+                        let first = ram.index(start);
+                        for page in &mut model[first..first + count] {
+                            *page = false;
+                        }
+
+                        ram.fill(start, count, tag);
+                        allocations.push((start, count, tag));
+                    }
+                }
+                Op::Free(i) if !allocations.is_empty() => {
+                    let (start, count, tag) = allocations.swap_remove(i % allocations.len());
+                    ram.check_filled(start, count, tag);
+
+                    unsafe { list.free(start, count) };
+
+                    let first = ram.index(start);
+                    for page in &mut model[first..first + count] {
+                        *page = true;
+                    }
+                }
+                Op::Free(_) => (),
+            }
+
+            check(&list, &model);
+        }
+
+        for (start, count, tag) in allocations {
+            ram.check_filled(start, count, tag);
+            unsafe { list.free(start, count) };
+        }
+
+        let expected = runs(&ram, &initial);
+        prop_assert_eq!(list.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn exhaustion((initial, order) in free_map(192), count in 1..8usize) {
+        let ram = FakeRam::new(0, initial.len());
+        let mut list = FreeList::new(&ram);
+
+        free_pages(&mut list, &initial, &order);
+
+        let mut allocated = Vec::new();
+        while let Some(start) = unsafe { list.alloc(count, PAGE_SIZE) } {
+            allocated.push(start);
+        }
+
+        // whatever is left over is too fragmented for another allocation
+        prop_assert!(list.iter().all(|range| range.count < count));
+        prop_assert_eq!(list.free_pages() + allocated.len() * count, initial.iter().filter(|&&f| f).count());
+
+        for start in allocated.into_iter().rev() {
+            unsafe { list.free(start, count) };
+        }
+
+        check(&list, &initial);
+        prop_assert_eq!(list.iter().collect::<Vec<_>>(), runs(&ram, &initial));
+    }
+
+    #[test]
+    fn boot_maps(map in boot_map(), sizes in proptest::collection::vec(1..32usize, 1..64)) {
+        let end = map.last().map_or(0, |(range, _)| range.end);
+        let ram = FakeRam::new(0, end.div_ceil(PAGE_SIZE) as usize);
+        let mut list = FreeList::new(&ram);
+
+        let usable = map.iter().filter(|(_, usable)| *usable).map(|(range, _)| range.clone());
+        let mut model = std::vec![false; ram.len];
+
+        for region in CombinedRegions::new(usable) {
+            let Some(pages) = whole_pages(region) else {
+                continue;
+            };
+
+            let count = ((pages.end - pages.start) / PAGE_SIZE) as usize;
+            unsafe { list.free(pages.start, count) };
+
+            let first = ram.index(pages.start);
+            for page in &mut model[first..first + count] {
+                *page = true;
+            }
+        }
+
+        check(&list, &model);
+
+        // every page handed out has to be backed by usable memory
+        for count in sizes {
+            let Some(start) = (unsafe { list.alloc(count, PAGE_SIZE) }) else {
+                continue;
+            };
+
+            for page in start / PAGE_SIZE..start / PAGE_SIZE + count as u64 {
+                let page = page * PAGE_SIZE..(page + 1) * PAGE_SIZE;
+                let covered: u64 = map
+                    .iter()
+                    .filter(|(_, usable)| *usable)
+                    .map(|(range, _)| range.end.min(page.end).saturating_sub(range.start.max(page.start)))
+                    .sum();
+
+                prop_assert_eq!(covered, PAGE_SIZE, "page {:#x} is not usable memory", page.start);
+            }
+
+            let first = ram.index(start);
+            for page in &mut model[first..first + count] {
+                *page = false;
+            }
+        }
+
+        check(&list, &model);
+    }
+}
+
+proptest! {
+    #[test]
+    fn buddy_matches_model((initial, order) in free_map(256), ops in proptest::collection::vec(op(), 1..200)) {
+        let ram = FakeRam::new(0x10_0000, initial.len());
+        let mut map = Vec::new();
+        let mut buddy = buddy(&ram, &mut map);
+
+        for &i in order.iter().filter(|&&i| initial[i]) {
+            unsafe { buddy.free(ram.addr(i), 1) };
+        }
+        check_buddy(&buddy, &initial);
+
+        let mut model = initial.clone();
+        let mut allocations: Vec<(u64, usize, u8)> = Vec::new();
+
+        for (tag, op) in ops.into_iter().enumerate() {
+            let tag = (tag % 255) as u8 + 1;
+
+            match op {
+                Op::Alloc { count, align_shift } => {
+                    let align = PAGE_SIZE << align_shift;
+
+                    if let Some(start) = unsafe { buddy.alloc(count, align) } {
+                        prop_assert!(start.is_multiple_of(align), "unaligned allocation {:#x}", start);
+
+                        let first = ram.index(start);
+                        prop_assert!(model[first..first + count].iter().all(|&f| f), "allocated used pages");
+                        for page in &mut model[first..first + count] {
+                            *page = false;
+                        }
+
+                        ram.fill(start, count, tag);
+                        allocations.push((start, count, tag));
+                    }
+                }
+                Op::Free(i) if !allocations.is_empty() => {
+                    let (start, count, tag) = allocations.swap_remove(i % allocations.len());
+                    ram.check_filled(start, count, tag);
+
+                    unsafe { buddy.free(start, count) };
+
+                    let first = ram.index(start);
+                    for page in &mut model[first..first + count] {
+                        *page = true;
+                    }
+                }
+                Op::Free(_) => (),
+            }
+
+            check_buddy(&buddy, &model);
+        }
+
+        for (start, count, tag) in allocations {
+            ram.check_filled(start, count, tag);
+            unsafe { buddy.free(start, count) };
+        }
+
+        check_buddy(&buddy, &initial);
+    }
+}
+
+#[test]
+fn split_keeps_head_and_tail() {
+    let ram = FakeRam::new(0x1000, 16);
+    let mut list = FreeList::new(&ram);
+
+    unsafe { list.free(0x1000, 16) };
+    let start = unsafe { list.alloc(2, 0x4000) };
+
+    assert_eq!(start, Some(0x4000));
+    assert_eq!(
+        list.iter().collect::<Vec<_>>(),
+        [
+            FreeRange {
+                start: 0x1000,
+                count: 3
+            },
+            FreeRange {
+                start: 0x6000,
+                count: 11
+            },
+        ]
+    );
+}
+
+#[test]
+fn free_merges_both_neighbours() {
+    let ram = FakeRam::new(0, 8);
+    let mut list = FreeList::new(&ram);
+
+    unsafe {
+        list.free(0x0000, 2);
+        list.free(0x4000, 4);
+        list.free(0x2000, 2);
+    }
+
+    assert_eq!(
+        list.iter().collect::<Vec<_>>(),
+        [FreeRange { start: 0, count: 8 }]
+    );
+}
+
+#[test]
+fn alloc_largest_takes_from_largest() {
+    let ram = FakeRam::new(0, 16);
+    let mut list = FreeList::new(&ram);
+
+    unsafe {
+        list.free(0x0000, 2);
+        list.free(0x4000, 8);
+    }
+
+    let range = unsafe { list.alloc_largest(5) };
+    assert_eq!(
+        range,
+        Some(FreeRange {
+            start: 0x4000,
+            count: 5
+        })
+    );
+    assert_eq!(
+        list.largest(),
+        Some(FreeRange {
+            start: 0x9000,
+            count: 3
+        })
+    );
+}
+
+#[test]
+#[should_panic(expected = "double free")]
+fn double_free_panics() {
+    let ram = FakeRam::new(0, 4);
+    let mut list = FreeList::new(&ram);
+
+    unsafe {
+        list.free(0x0000, 3);
+        list.free(0x2000, 1);
+    }
+}
+
+#[test]
+fn combines_adjacent_regions() {
+    let regions = [0..0x800, 0x800..0x3000, 0x4000..0x5000, 0x5000..0x5800];
+    let combined: Vec<_> = CombinedRegions::new(regions.into_iter()).collect();
+
+    assert_eq!(combined, [0..0x3000, 0x4000..0x5800]);
+    assert_eq!(whole_pages(0x800..0x3000), Some(0x1000..0x3000));
+    assert_eq!(whole_pages(0x4800..0x5800), None);
+}
+
+#[test]
+fn buddy_splits_and_merges() {
+    let ram = FakeRam::new(0, 16);
+    let mut map = Vec::new();
+    let mut buddy = buddy(&ram, &mut map);
+
+    unsafe { buddy.free(0, 16) };
+    assert_eq!(
+        buddy.blocks().collect::<Vec<_>>(),
+        [FreeRange {
+            start: 0,
+            count: 16
+        }]
+    );
+
+    let first = unsafe { buddy.alloc(3, PAGE_SIZE) };
+    assert_eq!(first, Some(0));
+    assert_eq!(buddy.free_pages(), 13);
+
+    let second = unsafe { buddy.alloc(1, 0x8000) };
+    assert_eq!(second, Some(0x8000));
+
+    unsafe {
+        buddy.free(0, 3);
+        buddy.free(0x8000, 1);
+    }
+    assert_eq!(
+        buddy.blocks().collect::<Vec<_>>(),
+        [FreeRange {
+            start: 0,
+            count: 16
+        }]
+    );
+}
+
+#[test]
+#[should_panic(expected = "double free")]
+fn buddy_double_free_panics() {
+    let ram = FakeRam::new(0, 4);
+    let mut map = Vec::new();
+    let mut buddy = buddy(&ram, &mut map);
+
+    unsafe {
+        buddy.free(0x0000, 4);
+        buddy.free(0x2000, 1);
+    }
+}